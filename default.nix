@@ -29,5 +29,9 @@ rustPlatform.buildRustPackage (finalAttrs: {
     ffmpeg_7
   ];
 
+  postInstall = ''
+    install -Dm644 config.schema.json $out/share/transcoder/config.schema.json
+  '';
+
   meta.mainProgram = "transcoder";
 })
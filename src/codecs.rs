@@ -0,0 +1,197 @@
+use clap::ValueEnum;
+use ez_ffmpeg::AVMediaType;
+use ffmpeg_sys_next::{
+    AVChannelLayout, AVCodec, AVCodecConfig, AVPixelFormat, AVSampleFormat,
+    av_channel_layout_describe, av_get_pix_fmt_name, av_get_sample_fmt_name,
+    avcodec_find_decoder_by_name, avcodec_find_encoder_by_name, avcodec_get_supported_config,
+};
+use serde::Serialize;
+use std::ffi::{CStr, CString, c_char, c_int, c_void};
+use std::fmt;
+use std::ptr::null;
+
+use crate::transcoder::{CodecInfoExtra, IndexedCodecs};
+
+// Codec as indexed for `supported-codecs`, which accepts both its name and long name. Lists of
+// formats and layouts are empty when codec accepts any or does not report them.
+#[derive(Debug, Serialize)]
+pub struct CodecDescription {
+    pub name: String,
+    pub long_name: String,
+    pub media_type: MediaKind,
+    pub encoder: bool,
+    pub decoder: bool,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub pixel_formats: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub sample_formats: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub channel_layouts: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum MediaKind {
+    Video,
+    Audio,
+    Subtitle,
+    Data,
+    Attachment,
+    Unknown,
+}
+
+#[derive(Debug, Clone, clap::Args)]
+pub struct CodecFilter {
+    /// Part of name or long name, case insensitive
+    pub pattern: Option<String>,
+    /// Only codecs of media type
+    #[arg(long = "type", value_enum)]
+    pub media_type: Option<MediaKind>,
+    /// Only codecs able to encode, i.e. to be transcoded into
+    #[arg(long)]
+    pub encoders: bool,
+    /// Only codecs able to decode
+    #[arg(long)]
+    pub decoders: bool,
+}
+
+// Indexed codecs matching filter sorted by name
+pub fn list(filter: &CodecFilter) -> Vec<CodecDescription> {
+    let pattern = filter.pattern.as_ref().map(|p| p.to_lowercase());
+    IndexedCodecs::get()
+        .codecs()
+        .into_iter()
+        .filter(|codec| {
+            pattern.as_ref().is_none_or(|p| {
+                codec.codec_name.to_lowercase().contains(p)
+                    || codec.codec_long_name.to_lowercase().contains(p)
+            })
+        })
+        .filter(|codec| !filter.encoders || codec.encoder)
+        .filter(|codec| !filter.decoders || codec.decoder)
+        .filter(|codec| {
+            filter
+                .media_type
+                .is_none_or(|kind| kind == MediaKind::from(codec.media_type))
+        })
+        .map(CodecDescription::of)
+        .collect()
+}
+
+impl CodecDescription {
+    // Formats are taken from encoder when there is one, as formats of decoders are rarely known
+    fn of(codec: &CodecInfoExtra) -> Self {
+        let mut description = Self {
+            name: codec.codec_name.clone(),
+            long_name: codec.codec_long_name.clone(),
+            media_type: codec.media_type.into(),
+            encoder: codec.encoder,
+            decoder: codec.decoder,
+            pixel_formats: vec![],
+            sample_formats: vec![],
+            channel_layouts: vec![],
+        };
+        let Ok(name) = CString::new(codec.codec_name.as_str()) else {
+            return description;
+        };
+        // Codecs are static in ffmpeg, so pointer stays valid
+        let avcodec = unsafe {
+            if codec.encoder {
+                avcodec_find_encoder_by_name(name.as_ptr())
+            } else {
+                avcodec_find_decoder_by_name(name.as_ptr())
+            }
+        };
+        if avcodec.is_null() {
+            return description;
+        }
+        description.pixel_formats =
+            supported::<AVPixelFormat>(avcodec, AVCodecConfig::AV_CODEC_CONFIG_PIX_FORMAT)
+                .into_iter()
+                .filter_map(|format| name_of(unsafe { av_get_pix_fmt_name(format) }))
+                .collect();
+        description.sample_formats =
+            supported::<AVSampleFormat>(avcodec, AVCodecConfig::AV_CODEC_CONFIG_SAMPLE_FORMAT)
+                .into_iter()
+                .filter_map(|format| name_of(unsafe { av_get_sample_fmt_name(format) }))
+                .collect();
+        description.channel_layouts =
+            supported::<AVChannelLayout>(avcodec, AVCodecConfig::AV_CODEC_CONFIG_CHANNEL_LAYOUT)
+                .iter()
+                .filter_map(describe_layout)
+                .collect();
+        description
+    }
+}
+
+impl fmt::Display for CodecDescription {
+    // Flags go first as in `ffmpeg -codecs`: decoder, encoder and media type
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self.media_type {
+            MediaKind::Video => 'V',
+            MediaKind::Audio => 'A',
+            MediaKind::Subtitle => 'S',
+            MediaKind::Data => 'D',
+            MediaKind::Attachment => 'T',
+            MediaKind::Unknown => '?',
+        };
+        writeln!(
+            f,
+            "{}{}{kind} {:24} {}",
+            if self.decoder { 'D' } else { '.' },
+            if self.encoder { 'E' } else { '.' },
+            self.name,
+            self.long_name
+        )?;
+        for (title, list) in [
+            ("pixel formats", &self.pixel_formats),
+            ("sample formats", &self.sample_formats),
+            ("channel layouts", &self.channel_layouts),
+        ] {
+            if !list.is_empty() {
+                writeln!(f, "    {title}: {}", list.join(", "))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl From<AVMediaType> for MediaKind {
+    fn from(media_type: AVMediaType) -> Self {
+        match media_type {
+            AVMediaType::AVMEDIA_TYPE_VIDEO => Self::Video,
+            AVMediaType::AVMEDIA_TYPE_AUDIO => Self::Audio,
+            AVMediaType::AVMEDIA_TYPE_SUBTITLE => Self::Subtitle,
+            AVMediaType::AVMEDIA_TYPE_DATA => Self::Data,
+            AVMediaType::AVMEDIA_TYPE_ATTACHMENT => Self::Attachment,
+            _ => Self::Unknown,
+        }
+    }
+}
+
+// Values of config supported by codec. ffmpeg reports no list when any value is accepted
+fn supported<T: Copy>(codec: *const AVCodec, config: AVCodecConfig) -> Vec<T> {
+    let mut configs: *const c_void = null();
+    let mut count: c_int = 0;
+    let ret =
+        unsafe { avcodec_get_supported_config(null(), codec, config, 0, &mut configs, &mut count) };
+    if ret < 0 || configs.is_null() || count <= 0 {
+        return vec![];
+    }
+    // The list is owned by codec, so it is copied
+    unsafe { std::slice::from_raw_parts(configs as *const T, count as usize) }.to_vec()
+}
+
+fn name_of(name: *const c_char) -> Option<String> {
+    (!name.is_null()).then(|| {
+        unsafe { CStr::from_ptr(name) }
+            .to_string_lossy()
+            .into_owned()
+    })
+}
+
+fn describe_layout(layout: &AVChannelLayout) -> Option<String> {
+    let mut buf = [0 as c_char; 64];
+    let ret = unsafe { av_channel_layout_describe(layout, buf.as_mut_ptr(), buf.len()) };
+    (ret > 0).then(|| name_of(buf.as_ptr())).flatten()
+}
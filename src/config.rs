@@ -0,0 +1,551 @@
+use serde::{Deserialize, Deserializer};
+use std::collections::{BTreeSet, HashSet};
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::net::SocketAddr;
+use std::path::{self, Path, PathBuf};
+use std::process::Stdio;
+
+use crate::profiles;
+use crate::retry::RetryConfig;
+use crate::rules::Rule;
+use crate::transcoder::{
+    CodecInfoExtra, FileExtension, Requirement, RequirementLevel, TranscoderConfig,
+    deserialize_codecs, deserialize_formats,
+};
+
+// Problem of configuration file. Location is known for syntax and type errors of formats which
+// report it.
+#[derive(Debug)]
+pub struct ConfigError {
+    pub path: PathBuf,
+    // Line and column, both starting from 1
+    pub location: Option<(usize, usize)>,
+    pub message: String,
+}
+
+// Partial configuration which is applied on top of some base one. The base is either the
+// referenced profile or the configuration layer is applied to.
+//
+// Lists of formats and codecs are replaced when specified, and extended with `extra-*` ones.
+// Requirements replace the ones of base with the same `what` and rules are appended.
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct ConfigLayer {
+    #[serde(default)]
+    pub profile: Option<String>,
+    #[serde(
+        default,
+        deserialize_with = "deserialize_some_formats",
+        alias = "supported-formats"
+    )]
+    pub supported_formats: Option<Vec<FileExtension>>,
+    #[serde(
+        default,
+        deserialize_with = "deserialize_formats",
+        alias = "extra-formats"
+    )]
+    pub extra_formats: Vec<FileExtension>,
+    #[serde(
+        default,
+        deserialize_with = "deserialize_some_codecs",
+        alias = "supported-codecs"
+    )]
+    pub supported_codecs: Option<Vec<CodecInfoExtra>>,
+    #[serde(
+        default,
+        deserialize_with = "deserialize_codecs",
+        alias = "extra-codecs"
+    )]
+    pub extra_codecs: Vec<CodecInfoExtra>,
+    #[serde(default, alias = "requirements")]
+    pub required: Option<BTreeSet<Requirement>>,
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+    #[serde(default)]
+    pub script: Option<PathBuf>,
+    #[serde(default)]
+    pub dryrun: Option<bool>,
+    #[serde(default)]
+    pub retry: Option<RetryConfig>,
+    #[serde(default, alias = "hash-sources")]
+    pub hash_sources: Option<bool>,
+    #[serde(default)]
+    pub state: Option<PathBuf>,
+    #[serde(default)]
+    pub socket: Option<PathBuf>,
+    #[serde(default)]
+    pub metrics: Option<SocketAddr>,
+    #[serde(default)]
+    pub events: Option<PathBuf>,
+    #[serde(default)]
+    pub outputs: Vec<OutputConfig>,
+    #[serde(default)]
+    pub watch: Vec<WatchConfig>,
+}
+
+// Overrides for the outputs placed into particular destination. Several destinations may be
+// watched for the same source, each with its own overrides.
+#[derive(Debug, Clone, Deserialize)]
+pub struct OutputConfig {
+    pub dst: PathBuf,
+    #[serde(flatten)]
+    pub layer: ConfigLayer,
+}
+
+// Watch pair declared in configuration with its own overrides or profile reference. Several
+// destinations may be listed for the same source.
+#[derive(Debug, Clone, Deserialize)]
+pub struct WatchConfig {
+    pub src: PathBuf,
+    #[serde(deserialize_with = "deserialize_one_or_many")]
+    pub dst: Vec<PathBuf>,
+    // Whether to check the whole source on start
+    #[serde(default = "default_recheck")]
+    pub recheck: bool,
+    #[serde(flatten)]
+    pub overrides: ConfigLayer,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OneOrMany<T> {
+    One(T),
+    Many(Vec<T>),
+}
+
+impl ConfigLayer {
+    pub fn apply(self, base: &TranscoderConfig) -> Result<TranscoderConfig, String> {
+        self.apply_chain(base, &mut vec![])
+    }
+
+    fn apply_chain(
+        self,
+        base: &TranscoderConfig,
+        chain: &mut Vec<String>,
+    ) -> Result<TranscoderConfig, String> {
+        let mut config = if let Some(name) = &self.profile {
+            if chain.contains(name) {
+                return Err(format!("Profile {name} references itself: {chain:?}"));
+            }
+            chain.push(name.clone());
+            let profile = profiles::load(name)?.apply_chain(&TranscoderConfig::default(), chain)?;
+            chain.pop();
+            // Profile replaces the policies of base, other options of base are kept
+            TranscoderConfig {
+                supported_formats: profile.supported_formats,
+                supported_codecs: profile.supported_codecs,
+                required: profile.required,
+                rules: profile.rules,
+                ..base.clone()
+            }
+        } else {
+            base.clone()
+        };
+
+        if let Some(formats) = self.supported_formats {
+            config.supported_formats = formats;
+        }
+        config.supported_formats.extend(self.extra_formats);
+        if let Some(codecs) = self.supported_codecs {
+            config.supported_codecs = codecs;
+        }
+        config.supported_codecs.extend(self.extra_codecs);
+        // Requirements replace inherited ones of the same streams, while ones of the same layer
+        // are all kept
+        if let Some(required) = self.required {
+            let replaced: Vec<_> = required.iter().map(|req| req.what.clone()).collect();
+            config
+                .required
+                .retain(|base| !replaced.contains(&base.what));
+            config.required.extend(required);
+        }
+        config.rules.extend(self.rules);
+        if self.script.is_some() {
+            config.script = self.script;
+        }
+        if let Some(retry) = self.retry {
+            config.retry = retry;
+        }
+        if let Some(hash_sources) = self.hash_sources {
+            config.hash_sources = hash_sources;
+        }
+        if self.state.is_some() {
+            config.state = self.state;
+        }
+        if self.socket.is_some() {
+            config.socket = self.socket;
+        }
+        if self.metrics.is_some() {
+            config.metrics = self.metrics;
+        }
+        if self.events.is_some() {
+            config.events = self.events;
+        }
+        if let Some(dryrun) = self.dryrun {
+            config.dryrun = dryrun;
+        }
+        config.outputs.extend(self.outputs);
+        config.watch.extend(self.watch);
+        Ok(config)
+    }
+}
+
+impl ConfigError {
+    pub fn new(path: &Path, message: impl ToString) -> Self {
+        Self {
+            path: path.to_owned(),
+            location: None,
+            message: message.to_string(),
+        }
+    }
+
+    fn at(path: &Path, location: Option<(usize, usize)>, message: impl ToString) -> Self {
+        Self {
+            location,
+            ..Self::new(path, message)
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.path.display())?;
+        if let Some((line, column)) = self.location {
+            write!(f, ":{line}:{column}")?;
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl TranscoderConfig {
+    // Reads configuration of type detected by extension: toml, json, yaml or nix
+    pub fn load(path: &Path) -> Result<TranscoderConfig, ConfigError> {
+        let error = |message: String| ConfigError::new(path, message);
+        let config_type = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .ok_or_else(|| error("Unknown config file type".to_string()))?
+            .to_lowercase();
+        let mut reader = File::open(path).map_err(|err| error(err.to_string()))?;
+
+        let config: TranscoderConfig = if config_type == "toml" {
+            let mut s = String::new();
+            reader
+                .read_to_string(&mut s)
+                .map_err(|err| error(err.to_string()))?;
+            toml::from_str(&s).map_err(|err| {
+                let location = err.span().map(|span| location(&s, span.start));
+                ConfigError::at(path, location, err.message())
+            })?
+        } else if config_type == "json" {
+            serde_json::from_reader(reader).map_err(|err| {
+                let location = (err.line() > 0).then(|| (err.line(), err.column()));
+                ConfigError::at(path, location, without_location(err.to_string()))
+            })?
+        } else if config_type == "yaml" {
+            serde_yaml::from_reader(reader).map_err(|err| {
+                let location = err.location().map(|loc| (loc.line(), loc.column()));
+                ConfigError::at(path, location, without_location(err.to_string()))
+            })?
+        } else if config_type == "nix" {
+            let stdout = std::process::Command::new("nix-instantiate")
+                .args(["--eval", "--json", "--strict"])
+                .arg(path)
+                .stderr(Stdio::inherit())
+                .output()
+                .map_err(|err| error(format!("Unable to process nix config: {err}")))?
+                .stdout;
+            let json = String::from_utf8(stdout).map_err(|err| error(err.to_string()))?;
+            // Location in evaluated JSON means nothing for nix file
+            serde_json::from_str(&json).map_err(|err| error(without_location(err.to_string())))?
+        } else {
+            return Err(error(format!("Unsupported config type {config_type}")));
+        };
+        // Configuration is validated for each target, as watch entries may set own profiles
+        Ok(config)
+    }
+
+    // Checks what deserialization does not: whether outputs can be placed with configuration
+    pub fn validate(&self) -> Result<(), String> {
+        if self.supported_formats.is_empty() {
+            return Err("`supported-formats` is empty".to_string());
+        }
+        if self.supported_codecs.is_empty() {
+            return Err("`supported-codecs` is empty".to_string());
+        }
+        if self.required.is_empty() && self.rules.is_empty() {
+            return Err("Neither `requirements` nor `rules` are given".to_string());
+        }
+        for policy in self.policies() {
+            let what = &policy.requirement.what;
+            if policy.when.is_some_and(|cond| cond.never_matches(what)) {
+                return Err(format!("Condition of {policy} can never match"));
+            }
+            // Streams are transcoded only into codecs with encoder
+            if matches!(
+                policy.get_level(),
+                RequirementLevel::All | RequirementLevel::AtLeastOne
+            ) && !self
+                .supported_codecs
+                .iter()
+                .any(|codec| codec.encoder && codec.media_type == what.media_type())
+            {
+                return Err(format!(
+                    "No codec with encoder in `supported-codecs` to transcode streams of {policy}"
+                ));
+            }
+        }
+        let mut pairs = HashSet::new();
+        for watch in self.watch.iter() {
+            for dst in watch.dst.iter() {
+                if !pairs.insert((&watch.src, dst)) {
+                    return Err(format!(
+                        "Watch pair {:?} -> {dst:?} is declared more than once",
+                        watch.src
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Effective configuration for outputs placed into dst
+    pub fn for_output(&self, dst: &Path) -> Result<TranscoderConfig, String> {
+        // The most specific destination wins when they are nested
+        let output = self
+            .outputs
+            .iter()
+            .filter_map(|output| {
+                let root = path::absolute(&output.dst).ok()?;
+                dst.starts_with(&root)
+                    .then(|| (root.components().count(), output))
+            })
+            .max_by_key(|(depth, _)| *depth)
+            .map(|(_, output)| output);
+        match output {
+            Some(output) => output.layer.clone().apply(self),
+            None => Ok(self.clone()),
+        }
+    }
+}
+
+impl TryFrom<ConfigLayer> for TranscoderConfig {
+    type Error = String;
+
+    fn try_from(layer: ConfigLayer) -> Result<Self, Self::Error> {
+        // Policies may be given by profiles of all watch entries instead
+        let profiled = !layer.watch.is_empty()
+            && layer
+                .watch
+                .iter()
+                .all(|watch| watch.overrides.profile.is_some());
+        if layer.profile.is_none() && !profiled {
+            if layer.supported_formats.is_none() {
+                return Err("missing field `supported-formats`".to_string());
+            }
+            if layer.supported_codecs.is_none() {
+                return Err("missing field `supported-codecs`".to_string());
+            }
+            if layer.required.is_none() {
+                return Err("missing field `requirements`".to_string());
+            }
+        }
+        layer.apply(&TranscoderConfig::default())
+    }
+}
+
+fn deserialize_some_formats<'de, D>(deserializer: D) -> Result<Option<Vec<FileExtension>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserialize_formats(deserializer).map(Some)
+}
+
+fn deserialize_some_codecs<'de, D>(deserializer: D) -> Result<Option<Vec<CodecInfoExtra>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserialize_codecs(deserializer).map(Some)
+}
+
+fn deserialize_one_or_many<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Ok(match OneOrMany::<T>::deserialize(deserializer)? {
+        OneOrMany::One(one) => vec![one],
+        OneOrMany::Many(many) => many,
+    })
+}
+
+// Line and column of byte offset in text
+fn location(text: &str, offset: usize) -> (usize, usize) {
+    let before = &text[..offset.min(text.len())];
+    let line = before.matches('\n').count() + 1;
+    let column = before
+        .rfind('\n')
+        .map_or(before, |nl| &before[nl + 1..])
+        .chars()
+        .count()
+        + 1;
+    (line, column)
+}
+
+// Location is reported separately, so it is cut from message of serde_json and serde_yaml
+fn without_location(message: String) -> String {
+    match message.rfind(" at line ") {
+        Some(at) => message[..at].to_string(),
+        None => message,
+    }
+}
+
+fn default_recheck() -> bool {
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::watcher::WatchPair;
+
+    fn config(text: &str) -> TranscoderConfig {
+        toml::from_str(text).unwrap()
+    }
+
+    #[test]
+    fn location_of_offset() {
+        let text = "a = 1\nb = \"ы\" c\n";
+        assert_eq!(location(text, 0), (1, 1));
+        assert_eq!(location(text, 4), (1, 5));
+        assert_eq!(location(text, 6), (2, 1));
+        // Columns are counted in characters
+        assert_eq!(location(text, text.find('c').unwrap()), (2, 9));
+        assert_eq!(location(text, 100), (3, 1));
+    }
+
+    #[test]
+    fn location_is_cut_from_message() {
+        assert_eq!(
+            without_location("unknown field `x` at line 2 column 3".to_string()),
+            "unknown field `x`"
+        );
+        assert_eq!(without_location("EOF".to_string()), "EOF");
+    }
+
+    #[test]
+    fn valid_config() {
+        config(
+            r#"
+            supported-formats = ["mp4"]
+            supported-codecs = ["libx264", "aac"]
+            requirements = [{ what = "Video", level = "All" }]
+            "#,
+        )
+        .validate()
+        .unwrap();
+    }
+
+    #[test]
+    fn requirements_of_one_layer_are_kept() {
+        let config = config(
+            r#"
+            supported-formats = ["mp4"]
+            supported-codecs = ["libx264"]
+            requirements = [
+                { what = "Video", level = "All" },
+                { what = "Video", level = "Ignore" },
+            ]
+            "#,
+        );
+        assert_eq!(config.required.len(), 2);
+    }
+
+    #[test]
+    fn empty_formats() {
+        let err = config(
+            r#"
+            supported-formats = []
+            supported-codecs = ["aac"]
+            requirements = [{ what = { Audio = {} }, level = "All" }]
+            "#,
+        )
+        .validate()
+        .unwrap_err();
+        assert_eq!(err, "`supported-formats` is empty");
+    }
+
+    #[test]
+    fn no_encoder_for_required_streams() {
+        let err = config(
+            r#"
+            supported-formats = ["mp4"]
+            supported-codecs = ["aac"]
+            requirements = [{ what = "Video", level = "All" }]
+            "#,
+        )
+        .validate()
+        .unwrap_err();
+        assert!(err.starts_with("No codec with encoder"), "{err}");
+    }
+
+    #[test]
+    fn rule_never_matching() {
+        let err = config(
+            r#"
+            supported-formats = ["mp4"]
+            supported-codecs = ["libx264"]
+            requirements = []
+
+            [[rules]]
+            name = "broken"
+            what = "Video"
+            level = "Drop"
+            when = { Stream = { what = { Audio = {} } } }
+            "#,
+        )
+        .validate()
+        .unwrap_err();
+        assert_eq!(err, "Condition of rule \"broken\" can never match");
+    }
+
+    #[test]
+    fn duplicate_watch_pair() {
+        let err = config(
+            r#"
+            profile = "generic-web"
+            watch = [
+                { src = "/media", dst = "/web" },
+                { src = "/media", dst = ["/tv", "/web"] },
+            ]
+            "#,
+        )
+        .validate()
+        .unwrap_err();
+        assert!(err.contains("declared more than once"), "{err}");
+    }
+
+    #[test]
+    fn policies_only_in_watch_profiles() {
+        let config = config(
+            r#"
+            watch = [
+                { src = "/media", dst = "/web", profile = "generic-web" },
+                { src = "/media", dst = "/music", profile = "audio-only-opus" },
+            ]
+            "#,
+        );
+        assert!(config.validate().is_err());
+        for watch in config.watch.iter() {
+            for target in WatchPair::from(watch.clone()).targets {
+                target
+                    .config_with(&config)
+                    .and_then(|config| config.validate())
+                    .unwrap();
+            }
+        }
+    }
+}
@@ -0,0 +1,219 @@
+use clap::Subcommand;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader as AsyncBufReader};
+use tokio::net::{UnixListener, UnixStream as AsyncUnixStream};
+
+use crate::progress::Progress;
+use crate::queue::{Job, Queue};
+use crate::state::{SourceRecord, State};
+use crate::watcher::{Pairs, Watcher};
+
+// Control API of running daemon. Requests and responses are JSON objects, one per line, sent
+// over Unix socket.
+#[derive(Debug, Clone, Subcommand, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "kebab-case")]
+pub enum Request {
+    /// List watched pairs
+    Pairs,
+    /// List queued, running and failed jobs
+    Jobs,
+    /// Stop starting queued jobs, the running one is finished
+    Pause,
+    /// Continue processing of queued jobs
+    Resume,
+    /// Cancel queued or running job
+    Cancel { id: u64 },
+    /// Check the file or directory for missed or outdated outputs
+    Recheck { path: PathBuf },
+    /// Process quarantined sources again
+    ClearQuarantine,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Response {
+    Ok(Value),
+    Error(String),
+}
+
+#[derive(Serialize)]
+struct JobStatus {
+    id: u64,
+    #[serde(flatten)]
+    job: Job,
+    running: bool,
+    progress: Vec<Progress>,
+}
+
+#[derive(Serialize)]
+struct FailedSource {
+    src: PathBuf,
+    #[serde(flatten)]
+    record: SourceRecord,
+}
+
+// Default socket is transcoder.sock inside XDG runtime directory or near the default state
+pub fn default_path() -> PathBuf {
+    std::env::var_os("XDG_RUNTIME_DIR")
+        .map(|dir| Path::new(&dir).join("transcoder.sock"))
+        .unwrap_or_else(|| State::default_path().with_file_name("control.sock"))
+}
+
+// Sends the request to daemon listening on socket and waits for response
+pub fn request(socket: &Path, request: &Request) -> Result<Value, String> {
+    let send = || -> io::Result<Response> {
+        let mut stream = UnixStream::connect(socket)?;
+        let mut line = serde_json::to_vec(request)?;
+        line.push(b'\n');
+        stream.write_all(&line)?;
+        let mut response = String::new();
+        BufReader::new(stream).read_line(&mut response)?;
+        Ok(serde_json::from_str(&response)?)
+    };
+    match send().map_err(|err| format!("Failed to reach daemon at {socket:?}: {err}"))? {
+        Response::Ok(value) => Ok(value),
+        Response::Error(err) => Err(err),
+    }
+}
+
+// Listens for requests until the process ends. Stale socket of previous run is replaced
+pub(crate) fn serve(socket: &Path, pairs: Pairs) -> io::Result<()> {
+    if let Some(dir) = socket.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    match std::fs::remove_file(socket) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+        _ => (),
+    }
+    let listener = UnixListener::bind(socket)?;
+    info!("Listening for control requests on {socket:?}");
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    tokio::spawn(handle_client(stream, pairs.clone()));
+                }
+                Err(err) => warn!("Failed to accept control connection: {err}"),
+            }
+        }
+    });
+    Ok(())
+}
+
+async fn handle_client(stream: AsyncUnixStream, pairs: Pairs) {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = AsyncBufReader::new(reader).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        let response = match serde_json::from_str::<Request>(&line) {
+            Ok(request) => {
+                debug!("Control request {request:?}");
+                match handle(request, &pairs) {
+                    Ok(value) => Response::Ok(value),
+                    Err(err) => Response::Error(err),
+                }
+            }
+            Err(err) => Response::Error(format!("Invalid request: {err}")),
+        };
+        let Ok(mut line) = serde_json::to_vec(&response) else {
+            break;
+        };
+        line.push(b'\n');
+        if writer.write_all(&line).await.is_err() {
+            break;
+        }
+    }
+}
+
+fn handle(request: Request, pairs: &Pairs) -> Result<Value, String> {
+    match request {
+        Request::Pairs => {
+            let pairs = pairs.read().unwrap();
+            Ok(pairs
+                .values()
+                .map(|wp| {
+                    json!({
+                        "src": wp.src,
+                        "dst": wp.targets.iter().map(|t| &t.dst).collect::<Vec<_>>(),
+                        "recheck": wp.recheck,
+                    })
+                })
+                .collect())
+        }
+        Request::Jobs => {
+            let progress = Progress::running();
+            let jobs: Vec<_> = Queue::jobs()
+                .into_iter()
+                .map(|(id, job, running)| JobStatus {
+                    id,
+                    running,
+                    progress: progress
+                        .iter()
+                        .filter(|p| running && p.src == job.src)
+                        .cloned()
+                        .collect(),
+                    job,
+                })
+                .collect();
+            let failed: Vec<_> = State::failed_sources()
+                .into_iter()
+                .map(|(src, record)| FailedSource { src, record })
+                .collect();
+            Ok(json!({
+                "paused": Queue::is_paused(),
+                "jobs": jobs,
+                "failed": failed,
+            }))
+        }
+        Request::Pause => {
+            info!("Pausing queue");
+            Queue::pause();
+            Ok(Value::Null)
+        }
+        Request::Resume => {
+            info!("Resuming queue");
+            Queue::unpause();
+            Ok(Value::Null)
+        }
+        Request::Cancel { id } => {
+            let job = Queue::cancel(id)?;
+            info!("Cancelled job {id} for {:?}", job.src);
+            Ok(Value::Null)
+        }
+        Request::Recheck { path } => {
+            let path = std::path::absolute(&path).map_err(|err| err.to_string())?;
+            let wp = pairs
+                .read()
+                .unwrap()
+                .values()
+                .find(|wp| path.starts_with(&wp.src))
+                .cloned()
+                .ok_or(format!("{path:?} is not watched"))?;
+            info!("Rechecking {path:?}");
+            Watcher::recheck_path(path, wp);
+            Ok(Value::Null)
+        }
+        Request::ClearQuarantine => {
+            let cleared = State::clear_quarantine();
+            info!("Cleared quarantine of {} sources", cleared.len());
+            let pairs = pairs.read().unwrap();
+            for src in cleared.iter() {
+                // Sources of detached pairs are left to recheck
+                if let Some(wp) = pairs.values().find(|wp| src.starts_with(&wp.src)) {
+                    Queue::push(Job {
+                        src: src.clone(),
+                        root: wp.src.clone(),
+                        check_exists: true,
+                        attempt: 0,
+                        not_before: None,
+                    });
+                }
+            }
+            Ok(cleared.len().into())
+        }
+    }
+}
@@ -0,0 +1,114 @@
+use log::warn;
+use serde::Serialize;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use crate::retry::FailureKind;
+use crate::transcoder::StreamPlan;
+
+// Structured log of decisions and outcomes, one JSON object per line. Disabled until opened.
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum Event<'a> {
+    // Source appeared or was met by recheck
+    Discovered {
+        src: &'a Path,
+        pair: &'a Path,
+    },
+    Probed {
+        src: &'a Path,
+        // Number of streams or None for files which are not media
+        streams: Option<usize>,
+        cached: bool,
+    },
+    Planned {
+        src: &'a Path,
+        dst: &'a Path,
+        output: &'a Path,
+        action: &'a str,
+        streams: &'a [StreamPlan],
+    },
+    JobStarted {
+        id: u64,
+        src: &'a Path,
+    },
+    JobFinished {
+        id: u64,
+        src: &'a Path,
+        #[serde(with = "seconds")]
+        elapsed: Duration,
+    },
+    JobFailed {
+        id: u64,
+        src: &'a Path,
+        #[serde(with = "seconds")]
+        elapsed: Duration,
+        kind: FailureKind,
+        errors: Vec<String>,
+    },
+    Published {
+        src: &'a Path,
+        output: &'a Path,
+    },
+    Removed {
+        output: &'a Path,
+        reason: &'a str,
+    },
+}
+
+#[derive(Serialize)]
+struct Record<'a> {
+    // Seconds since unix epoch
+    time: f64,
+    #[serde(flatten)]
+    event: Event<'a>,
+}
+
+static SINK: Mutex<Option<Box<dyn Write + Send>>> = Mutex::new(None);
+
+// Starts writing events to file, appending to it, or to stdout if path is "-"
+pub fn open(path: &Path) -> io::Result<()> {
+    let sink: Box<dyn Write + Send> = if path == Path::new("-") {
+        Box::new(io::stdout())
+    } else {
+        Box::new(OpenOptions::new().create(true).append(true).open(path)?)
+    };
+    *SINK.lock().unwrap_or_else(|err| err.into_inner()) = Some(sink);
+    Ok(())
+}
+
+pub(crate) fn emit(event: Event) {
+    let mut sink = SINK.lock().unwrap_or_else(|err| err.into_inner());
+    let Some(out) = sink.as_mut() else {
+        return;
+    };
+    let record = Record {
+        time: SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64(),
+        event,
+    };
+    let res = serde_json::to_vec(&record)
+        .map_err(io::Error::from)
+        .and_then(|mut line| {
+            line.push(b'\n');
+            out.write_all(&line)?;
+            out.flush()
+        });
+    if let Err(err) = res {
+        warn!("Failed to write event: {err}");
+    }
+}
+
+mod seconds {
+    use serde::Serializer;
+    use std::time::Duration;
+
+    pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_f64(duration.as_secs_f64())
+    }
+}
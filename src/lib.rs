@@ -1,2 +1,20 @@
 pub mod watcher;
+pub mod codecs;
+pub mod config;
+pub mod control;
+pub mod events;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+pub mod plan;
+pub mod reload;
+pub mod probe;
+pub mod profiles;
+pub mod progress;
+pub mod queue;
+pub mod retry;
+pub mod rules;
+pub mod schema;
+pub mod state;
+#[cfg(feature = "scripting")]
+mod script;
 pub mod transcoder;
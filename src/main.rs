@@ -1,75 +1,467 @@
-use clap::Parser;
-use log::{debug, info};
-use std::fs::File;
-use std::io::Read;
-use std::path::PathBuf;
-use std::process::Stdio;
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser, Subcommand};
+use log::{debug, info, warn};
+use std::fmt::Display;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use tokio;
-use transcoder::transcoder::TranscoderConfig;
+use transcoder::codecs::{self, CodecFilter};
+use transcoder::config::ConfigError;
+use transcoder::control::{self, Request};
+use transcoder::plan::{self, PlanFormat};
+use transcoder::reload::ConfigWatcher;
+use transcoder::schema;
+use transcoder::state::State;
+use transcoder::transcoder::{Transcoder, TranscoderConfig};
 use transcoder::watcher::{WatchPair, Watcher};
 
 #[derive(Parser, Debug)]
 struct Args {
-    #[arg(short, long, required = true)]
-    config: PathBuf,
-    #[arg(short, long)]
+    /// Configuration file. Required unless subcommand does not need it
+    #[arg(short, long, global = true)]
+    config: Option<PathBuf>,
+    /// Log what would be done without placing outputs
+    #[arg(short, long, global = true)]
     dryrun: bool,
-    pair: WatchPair,
+    /// Watching is performed when no subcommand is given
+    #[command(flatten)]
+    watch: WatchArgs,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(clap::Args, Debug, Clone)]
+struct PairArgs {
+    /// Source directory of pair. Each --src is paired with --dst of the same position
+    #[arg(long)]
+    src: Vec<PathBuf>,
+    /// Destination directory of pair
+    #[arg(long)]
+    dst: Vec<PathBuf>,
+    /// Pairs in form SRC:DST. Appended to the ones declared in configuration
     pairs: Vec<WatchPair>,
 }
 
+#[derive(clap::Args, Debug, Clone)]
+struct WatchArgs {
+    #[command(flatten)]
+    pairs: PairArgs,
+    /// Process quarantined sources again
+    #[arg(long)]
+    clear_quarantine: bool,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum Command {
+    /// Watch pairs and place outputs of changed sources until stopped. With --dryrun pairs are
+    /// checked once
+    Watch(WatchArgs),
+    /// Place missed and outdated outputs of all sources of pairs and exit
+    Scan(WatchArgs),
+    /// Place output of single file. Destination may be a directory to place output into
+    Transcode { src: PathBuf, dst: PathBuf },
+    /// Write plan of all sources without placing them
+    Plan {
+        #[command(flatten)]
+        pairs: PairArgs,
+        /// File to write plan to, "-" for stdout
+        #[arg(short, long, default_value = "-")]
+        output: PathBuf,
+        /// Format of plan. Detected by extension of output file by default
+        #[arg(long, value_enum)]
+        format: Option<PlanFormat>,
+    },
+    /// Show how the file is planned with the configuration
+    Explain { file: PathBuf },
+    /// List codecs of ffmpeg. Both name and long name may be used in supported-codecs
+    Codecs {
+        #[command(flatten)]
+        filter: CodecFilter,
+        /// Print as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Check configuration and exit
+    ValidateConfig,
+    /// Print JSON Schema of configuration
+    Schema {
+        /// Restrict codecs to the ones of local ffmpeg build, listed in lower case
+        #[arg(long)]
+        codecs: bool,
+    },
+    /// Send request to running daemon
+    Ctl {
+        /// Control socket of daemon. Taken from configuration when it is given
+        #[arg(long)]
+        socket: Option<PathBuf>,
+        #[command(subcommand)]
+        request: Request,
+    },
+}
+
 #[tokio::main]
 async fn main() {
     env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
     let args = Args::parse();
+    let command = args
+        .command
+        .clone()
+        .unwrap_or_else(|| Command::Watch(args.watch.clone()));
+
+    if args.config.is_none()
+        && !matches!(
+            command,
+            Command::Ctl { .. } | Command::Codecs { .. } | Command::Schema { .. }
+        )
+    {
+        Args::command()
+            .error(
+                ErrorKind::MissingRequiredArgument,
+                "the following required arguments were not provided: --config <CONFIG>",
+            )
+            .exit();
+    }
+
+    match command {
+        Command::Watch(watch) if !args.dryrun => watch_pairs(&args, &watch).await,
+        Command::Watch(watch) | Command::Scan(watch) => {
+            let (config, pairs) = load(&args, &watch.pairs).unwrap_or_else(|err| fail(err));
+            start(config, watch.clear_quarantine);
+            for pair in pairs {
+                info!("Checking {:?} -> {:?}", pair.src, pair.targets);
+                if let Err(err) = Watcher::recheck(pair.clone()).await {
+                    fail(format!("Failed to check {:?}: {err}", pair.src));
+                }
+            }
+        }
+        Command::Transcode { src, dst } => {
+            let config = load_config(&args).unwrap_or_else(|err| fail(err));
+            let pairs = WatchPair::merge(config.watch.iter().cloned().map(WatchPair::from));
+            let global = config.clone();
+            start(config, false);
+            if let Err(err) = transcode(&src, &dst, &global, pairs).await {
+                fail(err);
+            }
+        }
+        Command::Plan {
+            pairs,
+            output,
+            format,
+        } => {
+            let (config, pairs) = load(&args, &pairs).unwrap_or_else(|err| fail(err));
+            start(config, false);
+            let mut entries = vec![];
+            for pair in pairs {
+                match Watcher::plan(pair.clone()).await {
+                    Ok(plan) => entries.extend(plan),
+                    Err(err) => fail(format!("Failed to plan {:?}: {err}", pair.src)),
+                }
+            }
+            entries.sort_by(|a, b| (&a.src, &a.dst).cmp(&(&b.src, &b.dst)));
+            let format = format.unwrap_or_else(|| PlanFormat::for_path(&output));
+            if let Err(err) = plan::write(&entries, format, &output) {
+                fail(format!("Failed to write plan to {output:?}: {err}"));
+            }
+        }
+        Command::Explain { file } => {
+            let config = load_config(&args).unwrap_or_else(|err| fail(err));
+            let pairs = WatchPair::merge(config.watch.iter().cloned().map(WatchPair::from));
+            print!("{}", explain(&file, &config, pairs));
+        }
+        Command::Codecs { filter, json } => {
+            let codecs = codecs::list(&filter);
+            if json {
+                match serde_json::to_string_pretty(&codecs) {
+                    Ok(json) => println!("{json}"),
+                    Err(err) => fail(err),
+                }
+            } else {
+                codecs.iter().for_each(|codec| print!("{codec}"));
+            }
+        }
+        Command::ValidateConfig => {
+            let res = load_config(&args).and_then(|config| {
+                let pairs = WatchPair::merge(config.watch.iter().cloned().map(WatchPair::from));
+                validate(&config_path(&args), &config, &pairs)
+            });
+            match res {
+                Ok(()) => println!("Configuration {:?} is valid", config_path(&args)),
+                Err(err) => fail(err),
+            }
+        }
+        Command::Schema { codecs } => match serde_json::to_string_pretty(&schema::generate(codecs))
+        {
+            Ok(json) => println!("{json}"),
+            Err(err) => fail(err),
+        },
+        Command::Ctl { socket, request } => {
+            let socket = match (socket, &args.config) {
+                (Some(socket), _) => socket,
+                (None, Some(config)) => TranscoderConfig::load(config)
+                    .unwrap_or_else(|err| fail(err))
+                    .socket
+                    .unwrap_or_else(control::default_path),
+                (None, None) => control::default_path(),
+            };
+            match control::request(&socket, &request) {
+                Ok(serde_json::Value::Null) => (),
+                Ok(value) => println!("{value:#}"),
+                Err(err) => fail(err),
+            }
+        }
+    }
+}
+
+// Watches pairs until stopped, reloading configuration when it changes
+async fn watch_pairs(args: &Args, watch: &WatchArgs) {
+    let (config, pairs) = load(args, &watch.pairs).unwrap_or_else(|err| fail(err));
+    let socket = config.socket.clone().unwrap_or_else(control::default_path);
+    let metrics = config.metrics;
+    start(config, watch.clear_quarantine);
 
-    let config_type = args
-        .config
-        .extension()
-        .expect("Unknown config file type")
-        .to_str()
-        .expect("Unknown config file type")
-        .to_lowercase();
-    let mut reader = File::open(&args.config).unwrap();
-
-    let mut config: TranscoderConfig = if config_type == "toml" {
-        let mut s = String::new();
-        reader.read_to_string(&mut s).unwrap();
-        toml::from_str(&s).unwrap()
-    } else if config_type == "json" {
-        serde_json::from_reader(reader).unwrap()
-    } else if config_type == "yaml" {
-        serde_yaml::from_reader(reader).unwrap()
-    } else if config_type == "nix" {
-        let stdout = std::process::Command::new("nix-instantiate")
-            .args(["--eval", "--json", "--strict"])
-            .arg(&args.config)
-            .stderr(Stdio::inherit())
-            .output()
-            .expect("Unable to process nix config")
-            .stdout;
-        let json = String::from_utf8(stdout).unwrap();
-        serde_json::from_str(&json).unwrap()
+    let mut watcher = Watcher::new();
+    for pair in pairs {
+        info!("Watching {:?} -> {:?}", pair.src, pair.targets);
+        if let Err(err) = watcher.add(pair.clone()) {
+            fail(format!("Failed to watch {:?}: {err}", pair.src));
+        }
+    }
+    watcher.start_worker();
+    if let Err(err) = watcher.start_control(&socket) {
+        warn!("Failed to listen for control requests on {socket:?}: {err}");
+    }
+    if let Some(addr) = metrics {
+        serve_metrics(addr);
+    }
+    let path = config_path(args);
+    let mut config_watcher = ConfigWatcher::new(&path)
+        .unwrap_or_else(|err| fail(format!("Failed to watch {path:?}: {err}")));
+    loop {
+        tokio::select! {
+            more = watcher.next() => if !more {
+                break;
+            },
+            _ = config_watcher.changed() => {
+                info!("Reloading configuration {:?}", config_watcher.path());
+                match load(args, &watch.pairs) {
+                    Ok((config, pairs)) => {
+                        TranscoderConfig::set(config);
+                        if let Err(err) = watcher.update(pairs) {
+                            warn!("Failed to update watch pairs: {err}");
+                        }
+                    }
+                    Err(err) => warn!("Keeping previous configuration: {err}"),
+                }
+            }
+        }
+    }
+}
+
+// Opens state and event log of configuration and makes it global. Dry runs get a copy of state
+fn start(config: TranscoderConfig, clear_quarantine: bool) {
+    let state = config.state.clone().unwrap_or_else(State::default_path);
+    let res = if config.dryrun {
+        State::open_copy(&state)
     } else {
-        panic!("Unsupported config type ${config_type}")
+        State::open(&state)
     };
-    config.dryrun = args.dryrun || config.dryrun;
-    let dryrun = config.dryrun;
-    debug!("Configuration: {config:#?}");
+    if let Err(err) = res {
+        fail(format!("Failed to open state {state:?}: {err}"));
+    }
+    if let Some(events) = &config.events
+        && let Err(err) = transcoder::events::open(events)
+    {
+        warn!("Failed to open event log {events:?}: {err}");
+    }
+    if clear_quarantine {
+        info!(
+            "Cleared quarantine of {} sources",
+            State::clear_quarantine().len()
+        );
+    }
     TranscoderConfig::set(config);
+}
 
-    let pairs = std::iter::once(args.pair).chain(args.pairs.into_iter());
-    if !dryrun {
-        let mut watcher = Watcher::new();
-        for pair in pairs {
-            info!("Watching {:?} -> {:?}", pair.src, pair.dst);
-            watcher.add(pair).unwrap();
-        }
-        watcher.watch().await;
-    } else {
-        for pair in pairs {
-            info!("Checking {:?} -> {:?}", pair.src, pair.dst);
-            Watcher::recheck(pair).await.unwrap();
+// Loads configuration file shared by all subcommands
+fn load_config(args: &Args) -> Result<TranscoderConfig, ConfigError> {
+    let mut config = TranscoderConfig::load(&config_path(args))?;
+    config.dryrun =
+        args.dryrun || matches!(args.command, Some(Command::Plan { .. })) || config.dryrun;
+    debug!("Configuration: {config:#?}");
+    Ok(config)
+}
+
+// Loads and validates configuration with watch pairs from both configuration and arguments
+fn load(
+    args: &Args,
+    pair_args: &PairArgs,
+) -> Result<(TranscoderConfig, Vec<WatchPair>), ConfigError> {
+    if pair_args.src.len() != pair_args.dst.len() {
+        Args::command()
+            .error(
+                ErrorKind::WrongNumberOfValues,
+                "Each --src should have corresponding --dst",
+            )
+            .exit();
+    }
+    let config = load_config(args)?;
+    let pairs = WatchPair::merge(
+        config
+            .watch
+            .iter()
+            .cloned()
+            .map(WatchPair::from)
+            .chain(
+                pair_args
+                    .src
+                    .iter()
+                    .cloned()
+                    .zip(pair_args.dst.iter().cloned())
+                    .map(|(src, dst)| WatchPair::new(src, dst)),
+            )
+            .chain(pair_args.pairs.iter().cloned()),
+    );
+    let path = config_path(args);
+    if pairs.is_empty() {
+        return Err(ConfigError::new(
+            &path,
+            "No watch pairs neither in configuration nor in arguments",
+        ));
+    }
+    validate(&path, &config, &pairs)?;
+    Ok((config, pairs))
+}
+
+// Checks effective configuration of each target and that outputs are not placed into watched
+// source
+fn validate(
+    path: &Path,
+    config: &TranscoderConfig,
+    pairs: &[WatchPair],
+) -> Result<(), ConfigError> {
+    // Without pairs the global configuration is the effective one
+    if pairs.is_empty() {
+        return config.validate().map_err(|err| ConfigError::new(path, err));
+    }
+    for pair in pairs {
+        let pair = pair
+            .clone()
+            .absolute()
+            .map_err(|err| ConfigError::new(path, err))?;
+        for target in pair.targets.iter() {
+            if target.dst.starts_with(&pair.src) {
+                return Err(ConfigError::new(
+                    path,
+                    format!(
+                        "Destination {:?} is inside source {:?}",
+                        target.dst, pair.src
+                    ),
+                ));
+            }
+            target
+                .config_with(config)
+                .and_then(|config| config.validate())
+                .map_err(|err| {
+                    ConfigError::new(
+                        path,
+                        format!("Invalid configuration for {:?}: {err}", target.dst),
+                    )
+                })?;
         }
     }
+    Ok(())
+}
+
+fn fail(err: impl Display) -> ! {
+    eprintln!("{err}");
+    std::process::exit(1);
+}
+
+// Configuration is required unless subcommand does not need it
+fn config_path(args: &Args) -> PathBuf {
+    args.config.clone().expect("Configuration is required")
+}
+
+#[cfg(feature = "metrics")]
+fn serve_metrics(addr: SocketAddr) {
+    if let Err(err) = transcoder::metrics::serve(addr) {
+        warn!("Failed to serve metrics on {addr}: {err}");
+    }
+}
+
+#[cfg(not(feature = "metrics"))]
+fn serve_metrics(addr: SocketAddr) {
+    warn!("Built without metrics support, ignoring {addr}");
+}
+
+// Places the file with configuration of watch target the destination belongs to or with the
+// global one
+async fn transcode(
+    src: &Path,
+    dst: &Path,
+    config: &TranscoderConfig,
+    pairs: Vec<WatchPair>,
+) -> Result<(), String> {
+    let src = std::path::absolute(src).map_err(|err| err.to_string())?;
+    if !src.is_file() {
+        return Err(format!("{src:?} is not a file"));
+    }
+    let mut dst = std::path::absolute(dst).map_err(|err| err.to_string())?;
+    if dst.is_dir()
+        && let Some(name) = src.file_name()
+    {
+        dst.push(name);
+    }
+    let target = pairs
+        .into_iter()
+        .filter_map(|pair| pair.absolute().ok())
+        .flat_map(|pair| pair.targets)
+        .filter(|target| dst.starts_with(&target.dst))
+        .max_by_key(|target| target.dst.components().count());
+    let config = match target {
+        Some(target) => target.config_with(config),
+        None => config.for_output(&dst),
+    }
+    .and_then(|config| config.validate().map(|()| config))
+    .map_err(|err| format!("Invalid configuration for {dst:?}: {err}"))?;
+    info!("Transcoding {src:?} -> {dst:?}");
+    let results = tokio::task::spawn_blocking(move || {
+        Transcoder::get().transcode(&src, &[(dst, config)], false)
+    })
+    .await
+    .map_err(|err| err.to_string())?;
+    results
+        .into_iter()
+        .collect::<Result<(), _>>()
+        .map_err(|err| format!("Failed to transcode: {err}"))
+}
+
+// Explains the file for each destination it is placed to or with the global configuration if
+// it is not watched
+fn explain(file: &Path, config: &TranscoderConfig, pairs: Vec<WatchPair>) -> String {
+    let file = std::path::absolute(file).unwrap_or_else(|_| file.to_owned());
+    let targets: Vec<_> = pairs
+        .into_iter()
+        .filter_map(|pair| pair.absolute().ok())
+        .filter_map(|pair| {
+            let suffix = file.strip_prefix(&pair.src).ok()?.to_owned();
+            Some(
+                pair.targets
+                    .into_iter()
+                    .map(move |t| (t.dst.join(&suffix), t)),
+            )
+        })
+        .flatten()
+        .collect();
+    if targets.is_empty() {
+        return Transcoder::explain(&file, &file, config);
+    }
+    targets
+        .iter()
+        .map(|(dst, target)| match target.config_with(config) {
+            Ok(config) => format!("For {dst:?}:\n{}", Transcoder::explain(&file, dst, &config)),
+            Err(err) => format!("Invalid configuration for {dst:?}: {err}\n"),
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
 }
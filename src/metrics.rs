@@ -0,0 +1,277 @@
+use log::{info, trace, warn};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{LazyLock, Mutex, MutexGuard};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::state::State;
+use crate::transcoder::Transcoder;
+
+// Metrics in Prometheus text format served over plain HTTP. Counters live in memory, so they
+// start from zero on each run.
+struct Metrics {
+    // Events received per source of watch pair
+    events: BTreeMap<PathBuf, u64>,
+    jobs_succeeded: u64,
+    jobs_failed: u64,
+    bytes_in: u64,
+    bytes_out: u64,
+    probe_failures: u64,
+    overflows: u64,
+    duration: Histogram<11>,
+    speed: Histogram<8>,
+}
+
+struct Histogram<const N: usize> {
+    bounds: [f64; N],
+    counts: [u64; N],
+    sum: f64,
+    count: u64,
+}
+
+static METRICS: LazyLock<Mutex<Metrics>> = LazyLock::new(Default::default);
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            events: BTreeMap::new(),
+            jobs_succeeded: 0,
+            jobs_failed: 0,
+            bytes_in: 0,
+            bytes_out: 0,
+            probe_failures: 0,
+            overflows: 0,
+            duration: Histogram::durations(),
+            speed: Histogram::speeds(),
+        }
+    }
+}
+
+impl Histogram<11> {
+    // Transcoding duration in seconds
+    fn durations() -> Self {
+        Self::new([
+            1.0, 5.0, 10.0, 30.0, 60.0, 300.0, 600.0, 1800.0, 3600.0, 7200.0, 14400.0,
+        ])
+    }
+}
+
+impl Histogram<8> {
+    // Transcoding speed relative to playback
+    fn speeds() -> Self {
+        Self::new([0.1, 0.25, 0.5, 1.0, 2.0, 4.0, 8.0, 16.0])
+    }
+}
+
+impl<const N: usize> Histogram<N> {
+    fn new(bounds: [f64; N]) -> Self {
+        Self {
+            bounds,
+            counts: [0; N],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        for (bound, count) in self.bounds.iter().zip(self.counts.iter_mut()) {
+            if value <= *bound {
+                *count += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} histogram");
+        for (bound, count) in self.bounds.iter().zip(self.counts.iter()) {
+            let _ = writeln!(out, "{name}_bucket{{le=\"{bound}\"}} {count}");
+        }
+        let _ = writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {}", self.count);
+        let _ = writeln!(out, "{name}_sum {}", self.sum);
+        let _ = writeln!(out, "{name}_count {}", self.count);
+    }
+}
+
+pub(crate) fn event(pair: &Path) {
+    *lock().events.entry(pair.to_owned()).or_default() += 1;
+}
+
+pub(crate) fn overflow() {
+    lock().overflows += 1;
+}
+
+pub(crate) fn probe_failed() {
+    lock().probe_failures += 1;
+}
+
+// Job failed if any of its outputs failed, cancelled jobs are not counted
+pub(crate) fn job_finished(results: &[(PathBuf, io::Result<()>)]) {
+    let mut metrics = lock();
+    let errors = results.iter().filter_map(|(_, res)| res.as_ref().err());
+    if errors.clone().any(|err| !Transcoder::is_cancelled(err)) {
+        metrics.jobs_failed += 1;
+    } else if !results.is_empty() && errors.count() == 0 {
+        metrics.jobs_succeeded += 1;
+    }
+}
+
+// Source duration is used to compute speed, it is unknown for files which are not media
+pub(crate) fn transcoded(src: &Path, dst: &Path, elapsed: Duration, duration: Option<Duration>) {
+    let size = |path: &Path| path.metadata().map(|stat| stat.len()).unwrap_or_default();
+    let mut metrics = lock();
+    metrics.bytes_in += size(src);
+    metrics.bytes_out += size(dst);
+    metrics.duration.observe(elapsed.as_secs_f64());
+    if let Some(duration) = duration
+        && !elapsed.is_zero()
+    {
+        metrics
+            .speed
+            .observe(duration.as_secs_f64() / elapsed.as_secs_f64());
+    }
+}
+
+pub fn serve(addr: SocketAddr) -> io::Result<()> {
+    let listener = std::net::TcpListener::bind(addr)?;
+    listener.set_nonblocking(true)?;
+    let listener = TcpListener::from_std(listener)?;
+    info!("Serving metrics on http://{addr}/metrics");
+    // Metrics include paths of sources and are served without authentication
+    if !addr.ip().is_loopback() {
+        warn!("Metrics on {addr} are reachable from other hosts, prefer a loopback address");
+    }
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    tokio::spawn(async move {
+                        if let Err(err) = respond(stream).await {
+                            trace!("Failed to serve metrics: {err}");
+                        }
+                    });
+                }
+                Err(err) => warn!("Failed to accept metrics connection: {err}"),
+            }
+        }
+    });
+    Ok(())
+}
+
+// Only the request line matters, the rest of request is ignored
+async fn respond(mut stream: TcpStream) -> io::Result<()> {
+    let mut buf = [0; 1024];
+    let len = stream.read(&mut buf).await?;
+    let request = String::from_utf8_lossy(&buf[..len]);
+    let response = if request.starts_with("GET /metrics ") {
+        let body = render();
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        )
+    } else {
+        "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+    };
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+fn render() -> String {
+    let jobs = State::jobs();
+    let running = jobs.iter().filter(|(_, _, running)| *running).count();
+    let metrics = lock();
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "# HELP transcoder_events_total Events received per watch pair\n# TYPE transcoder_events_total counter"
+    );
+    for (pair, count) in metrics.events.iter() {
+        let _ = writeln!(
+            out,
+            "transcoder_events_total{{pair=\"{}\"}} {count}",
+            escape(&pair.to_string_lossy())
+        );
+    }
+    let mut metric = |name: &str, kind: &str, help: &str, value: u64| {
+        let _ = writeln!(
+            out,
+            "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}"
+        );
+    };
+    metric(
+        "transcoder_jobs_queued",
+        "gauge",
+        "Jobs waiting in queue",
+        (jobs.len() - running) as u64,
+    );
+    metric(
+        "transcoder_jobs_running",
+        "gauge",
+        "Jobs being processed",
+        running as u64,
+    );
+    metric(
+        "transcoder_jobs_succeeded_total",
+        "counter",
+        "Jobs with all outputs placed",
+        metrics.jobs_succeeded,
+    );
+    metric(
+        "transcoder_jobs_failed_total",
+        "counter",
+        "Jobs with failed outputs",
+        metrics.jobs_failed,
+    );
+    metric(
+        "transcoder_bytes_in_total",
+        "counter",
+        "Size of transcoded sources",
+        metrics.bytes_in,
+    );
+    metric(
+        "transcoder_bytes_out_total",
+        "counter",
+        "Size of transcoded outputs",
+        metrics.bytes_out,
+    );
+    metric(
+        "transcoder_probe_failures_total",
+        "counter",
+        "Files failed to probe, including ones which are not media",
+        metrics.probe_failures,
+    );
+    metric(
+        "transcoder_inotify_overflows_total",
+        "counter",
+        "Overflows of inotify event queue",
+        metrics.overflows,
+    );
+    metrics.duration.render(
+        &mut out,
+        "transcoder_transcode_duration_seconds",
+        "Time spent on transcoding",
+    );
+    metrics.speed.render(
+        &mut out,
+        "transcoder_transcode_speed",
+        "Transcoding speed relative to playback",
+    );
+    out
+}
+
+fn escape(label: &str) -> String {
+    label
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn lock<'a>() -> MutexGuard<'a, Metrics> {
+    METRICS.lock().unwrap_or_else(|err| err.into_inner())
+}
@@ -0,0 +1,89 @@
+use clap::ValueEnum;
+use serde::Serialize;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use crate::state::State;
+use crate::transcoder::{OutputPlan, StreamPlan};
+
+// Plan of one output as exported for review. Entries are sorted by source, so plans of different
+// configurations may be compared with diff.
+#[derive(Debug, Serialize)]
+pub struct PlanEntry {
+    pub src: PathBuf,
+    pub dst: PathBuf,
+    // Actual path of the output, it may have another extension than destination
+    pub output: PathBuf,
+    // One of symlink, remux, transcode or skip
+    pub action: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub streams: Vec<StreamPlan>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub ffmpeg: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum PlanFormat {
+    Json,
+    Yaml,
+}
+
+impl PlanEntry {
+    pub fn planned(dst: &Path, plan: OutputPlan) -> Self {
+        Self {
+            action: plan.kind(),
+            ffmpeg: plan
+                .ffmpeg_args()
+                .iter()
+                .map(|arg| arg.to_string_lossy().into_owned())
+                .collect(),
+            src: plan.src,
+            dst: dst.to_owned(),
+            output: plan.dst,
+            reason: plan.reason,
+            streams: plan.streams,
+        }
+    }
+
+    // Output is not going to be placed. The recorded output is reported if there is one
+    pub fn skipped(src: &Path, dst: &Path, reason: &str) -> Self {
+        Self {
+            src: src.to_owned(),
+            dst: dst.to_owned(),
+            output: State::output(dst).map_or_else(|| dst.to_owned(), |record| record.output),
+            action: "skip",
+            reason: Some(reason.to_string()),
+            streams: vec![],
+            ffmpeg: vec![],
+        }
+    }
+}
+
+impl PlanFormat {
+    // YAML for .yaml and .yml files, JSON otherwise
+    pub fn for_path(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("yaml" | "yml") => Self::Yaml,
+            _ => Self::Json,
+        }
+    }
+}
+
+// Writes plan to file or to stdout if path is "-"
+pub fn write(entries: &[PlanEntry], format: PlanFormat, path: &Path) -> Result<(), String> {
+    let mut out: Box<dyn Write> = if path == Path::new("-") {
+        Box::new(io::stdout())
+    } else {
+        Box::new(File::create(path).map_err(|err| err.to_string())?)
+    };
+    match format {
+        PlanFormat::Json => {
+            serde_json::to_writer_pretty(&mut out, entries).map_err(|err| err.to_string())?;
+            writeln!(out).map_err(|err| err.to_string())
+        }
+        PlanFormat::Yaml => serde_yaml::to_writer(out, entries).map_err(|err| err.to_string()),
+    }
+}
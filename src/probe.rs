@@ -0,0 +1,295 @@
+use ez_ffmpeg::AVRational;
+use ez_ffmpeg::stream_info::{StreamInfo, find_all_stream_infos};
+use ffmpeg_sys_next::{AVChannelOrder, AVCodecID, avcodec_descriptor_get_by_name};
+use log::trace;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::events::{self, Event};
+use crate::state::State;
+
+// Probe results are cached by identity of file, so renamed files are not probed again and
+// changed ones are probed anew. Failed probes are not cached, as file may be not media as well as
+// temporarily unreadable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProbeKey {
+    pub dev: u64,
+    pub ino: u64,
+    pub size: u64,
+    pub mtime: i64,
+    pub mtime_nsec: i64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Probe {
+    pub streams: Option<Vec<CachedStream>>,
+}
+
+pub(crate) struct CachedStream(pub StreamInfo);
+
+impl ProbeKey {
+    pub fn of(path: &Path) -> Option<Self> {
+        let stat = path.metadata().ok()?;
+        Some(Self {
+            dev: stat.dev(),
+            ino: stat.ino(),
+            size: stat.size(),
+            mtime: stat.mtime(),
+            mtime_nsec: stat.mtime_nsec(),
+        })
+    }
+
+    pub(crate) fn to_bytes(self) -> Vec<u8> {
+        [self.dev, self.ino, self.size]
+            .into_iter()
+            .chain([self.mtime as u64, self.mtime_nsec as u64])
+            .flat_map(u64::to_be_bytes)
+            .collect()
+    }
+}
+
+// Streams of media file or None if file is not media
+pub(crate) fn probe(path: &Path) -> Option<Vec<StreamInfo>> {
+    let key = ProbeKey::of(path);
+    // Failures may be cached by previous versions
+    if let Some(streams) = key.and_then(State::probe).and_then(|probe| probe.streams) {
+        trace!("Using cached probe of {path:?}");
+        events::emit(Event::Probed {
+            src: path,
+            streams: Some(streams.len()),
+            cached: true,
+        });
+        return Some(streams.into_iter().map(|s| s.0).collect());
+    }
+    let streams = find_all_stream_infos(path.as_os_str().to_str()?).ok();
+    events::emit(Event::Probed {
+        src: path,
+        streams: streams.as_ref().map(Vec::len),
+        cached: false,
+    });
+    #[cfg(feature = "metrics")]
+    if streams.is_none() {
+        crate::metrics::probe_failed();
+    }
+    // File may be changed while probed
+    if let Some(key) = key
+        && let Some(streams) = &streams
+        && ProbeKey::of(path) == Some(key)
+    {
+        let probe = Probe {
+            streams: Some(streams.iter().cloned().map(CachedStream).collect()),
+        };
+        State::record_probe(path, key, &probe);
+    }
+    streams
+}
+
+// Duration of the longest stream of media file
+pub(crate) fn duration(path: &Path) -> Option<Duration> {
+    probe(path)?
+        .iter()
+        .filter_map(|stream| {
+            let (duration, time_base) = match stream {
+                StreamInfo::Video {
+                    duration,
+                    time_base,
+                    ..
+                }
+                | StreamInfo::Audio {
+                    duration,
+                    time_base,
+                    ..
+                }
+                | StreamInfo::Subtitle {
+                    duration,
+                    time_base,
+                    ..
+                }
+                | StreamInfo::Data {
+                    duration,
+                    time_base,
+                    ..
+                } => (*duration, time_base),
+                StreamInfo::Attachment { .. } | StreamInfo::Unknown { .. } => return None,
+            };
+            // Unknown duration is reported as negative
+            (duration > 0 && time_base.num > 0 && time_base.den > 0).then(|| {
+                Duration::from_secs_f64(
+                    duration as f64 * time_base.num as f64 / time_base.den as f64,
+                )
+            })
+        })
+        .max()
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(remote = "StreamInfo")]
+enum StreamInfoDef {
+    Video {
+        index: i32,
+        #[serde(with = "AVRationalDef")]
+        time_base: AVRational,
+        start_time: i64,
+        duration: i64,
+        nb_frames: i64,
+        #[serde(with = "AVRationalDef")]
+        r_frame_rate: AVRational,
+        #[serde(with = "AVRationalDef")]
+        sample_aspect_ratio: AVRational,
+        metadata: HashMap<String, String>,
+        #[serde(with = "AVRationalDef")]
+        avg_frame_rate: AVRational,
+        #[serde(skip, default = "unknown_codec")]
+        codec_id: AVCodecID,
+        codec_name: String,
+        width: i32,
+        height: i32,
+        bit_rate: i64,
+        pixel_format: i32,
+        video_delay: i32,
+        fps: f64,
+        rotate: i32,
+    },
+    Audio {
+        index: i32,
+        #[serde(with = "AVRationalDef")]
+        time_base: AVRational,
+        start_time: i64,
+        duration: i64,
+        nb_frames: i64,
+        metadata: HashMap<String, String>,
+        #[serde(with = "AVRationalDef")]
+        avg_frame_rate: AVRational,
+        #[serde(skip, default = "unknown_codec")]
+        codec_id: AVCodecID,
+        codec_name: String,
+        sample_rate: i32,
+        #[serde(with = "channel_order")]
+        order: AVChannelOrder,
+        nb_channels: i32,
+        bit_rate: i64,
+        sample_format: i32,
+        frame_size: i32,
+    },
+    Subtitle {
+        index: i32,
+        #[serde(with = "AVRationalDef")]
+        time_base: AVRational,
+        start_time: i64,
+        duration: i64,
+        nb_frames: i64,
+        metadata: HashMap<String, String>,
+        #[serde(skip, default = "unknown_codec")]
+        codec_id: AVCodecID,
+        codec_name: String,
+    },
+    Data {
+        index: i32,
+        #[serde(with = "AVRationalDef")]
+        time_base: AVRational,
+        start_time: i64,
+        duration: i64,
+        metadata: HashMap<String, String>,
+    },
+    Attachment {
+        index: i32,
+        metadata: HashMap<String, String>,
+        #[serde(skip, default = "unknown_codec")]
+        codec_id: AVCodecID,
+        codec_name: String,
+    },
+    Unknown {
+        index: i32,
+        metadata: HashMap<String, String>,
+    },
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(remote = "AVRational")]
+struct AVRationalDef {
+    num: i32,
+    den: i32,
+}
+
+// Codec id is restored from codec name, which is its descriptor name. Numeric ids are not stored
+// as they may differ between ffmpeg versions.
+fn unknown_codec() -> AVCodecID {
+    AVCodecID::AV_CODEC_ID_NONE
+}
+
+impl<'de> Deserialize<'de> for CachedStream {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut stream = StreamInfoDef::deserialize(deserializer)?;
+        match &mut stream {
+            StreamInfo::Video {
+                codec_id,
+                codec_name,
+                ..
+            }
+            | StreamInfo::Audio {
+                codec_id,
+                codec_name,
+                ..
+            }
+            | StreamInfo::Subtitle {
+                codec_id,
+                codec_name,
+                ..
+            }
+            | StreamInfo::Attachment {
+                codec_id,
+                codec_name,
+                ..
+            } => {
+                *codec_id = codec_by_name(codec_name).ok_or_else(|| {
+                    serde::de::Error::custom(format!("Unknown codec {codec_name}"))
+                })?;
+            }
+            StreamInfo::Data { .. } | StreamInfo::Unknown { .. } => (),
+        }
+        Ok(Self(stream))
+    }
+}
+
+impl Serialize for CachedStream {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        StreamInfoDef::serialize(&self.0, serializer)
+    }
+}
+
+fn codec_by_name(name: &str) -> Option<AVCodecID> {
+    if name == "none" {
+        return Some(AVCodecID::AV_CODEC_ID_NONE);
+    }
+    let name = CString::new(name).ok()?;
+    // Descriptors are static in ffmpeg, so pointer stays valid
+    let desc = unsafe { avcodec_descriptor_get_by_name(name.as_ptr()) };
+    unsafe { desc.as_ref() }.map(|desc| desc.id)
+}
+
+mod channel_order {
+    use ffmpeg_sys_next::AVChannelOrder;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        order: &AVChannelOrder,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u32(*order as u32)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<AVChannelOrder, D::Error> {
+        Ok(match u32::deserialize(deserializer)? {
+            1 => AVChannelOrder::AV_CHANNEL_ORDER_NATIVE,
+            2 => AVChannelOrder::AV_CHANNEL_ORDER_CUSTOM,
+            3 => AVChannelOrder::AV_CHANNEL_ORDER_AMBISONIC,
+            _ => AVChannelOrder::AV_CHANNEL_ORDER_UNSPEC,
+        })
+    }
+}
@@ -0,0 +1,150 @@
+use crate::config::ConfigLayer;
+
+// Named device profiles shipped with transcoder. Configuration references one with `profile`
+// key and overrides or extends its fields.
+const PROFILES: &[(&str, &str)] = &[
+    ("generic-web", include_str!("profiles/generic-web.toml")),
+    (
+        "chromecast-gen3",
+        include_str!("profiles/chromecast-gen3.toml"),
+    ),
+    ("apple-tv", include_str!("profiles/apple-tv.toml")),
+    ("roku", include_str!("profiles/roku.toml")),
+    ("lg-webos", include_str!("profiles/lg-webos.toml")),
+    (
+        "audio-only-opus",
+        include_str!("profiles/audio-only-opus.toml"),
+    ),
+];
+
+pub fn names() -> impl Iterator<Item = &'static str> {
+    PROFILES.iter().map(|(name, _)| *name)
+}
+
+pub fn get(name: &str) -> Option<&'static str> {
+    PROFILES
+        .iter()
+        .find(|(n, _)| *n == name)
+        .map(|(_, profile)| *profile)
+}
+
+pub fn load(name: &str) -> Result<ConfigLayer, String> {
+    let profile = get(name).ok_or_else(|| format!("Unknown profile {name}"))?;
+    toml::from_str(profile).map_err(|err| format!("Invalid profile {name}: {err}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transcoder::{IndexedCodecs, RequirementLevel, TranscoderConfig};
+
+    fn check_profile(name: &str) {
+        let config = load(name)
+            .and_then(|layer| layer.apply(&TranscoderConfig::default()))
+            .unwrap_or_else(|err| panic!("{name}: {err}"));
+        assert!(!config.supported_formats.is_empty(), "{name}");
+        assert!(!config.supported_codecs.is_empty(), "{name}");
+        assert!(!config.required.is_empty(), "{name}");
+        config
+            .validate()
+            .unwrap_or_else(|err| panic!("{name}: {err}"));
+
+        let codecs = IndexedCodecs::new();
+        for codec in config.supported_codecs.iter() {
+            assert!(
+                codecs.find_in(&codec.codec_name).is_some(),
+                "{name}: unknown codec {}",
+                codec.codec_name
+            );
+        }
+        // Streams required to be transcoded need an encoder of their type
+        for req in config.required.iter().filter(|req| {
+            matches!(
+                req.level,
+                RequirementLevel::All | RequirementLevel::AtLeastOne
+            )
+        }) {
+            assert!(
+                config.supported_codecs.iter().any(|codec| {
+                    codecs
+                        .find_in(&codec.codec_name)
+                        .is_some_and(|codec| codec.encoder)
+                        && codec.media_type == req.what.media_type()
+                }),
+                "{name}: no encoder for {:?}",
+                req.what
+            );
+        }
+    }
+
+    #[test]
+    fn all_profiles_are_tested() {
+        assert_eq!(names().count(), 6);
+    }
+
+    #[test]
+    fn generic_web() {
+        check_profile("generic-web");
+    }
+
+    #[test]
+    fn chromecast_gen3() {
+        check_profile("chromecast-gen3");
+    }
+
+    #[test]
+    fn apple_tv() {
+        check_profile("apple-tv");
+    }
+
+    #[test]
+    fn roku() {
+        check_profile("roku");
+    }
+
+    #[test]
+    fn lg_webos() {
+        check_profile("lg-webos");
+    }
+
+    #[test]
+    fn audio_only_opus() {
+        check_profile("audio-only-opus");
+    }
+
+    #[test]
+    fn profile_is_extended() {
+        let config: TranscoderConfig = toml::from_str(
+            r#"
+            profile = "generic-web"
+            extra-formats = ["MKV"]
+            requirements = [{ what = "Video", level = "Ignore" }]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.supported_formats, ["mp4", "m4v", "mkv"]);
+        assert_eq!(config.required.len(), 3);
+    }
+
+    #[test]
+    fn profile_keeps_options_of_base() {
+        let base: TranscoderConfig = toml::from_str(
+            r#"
+            profile = "roku"
+            script = "plan.rhai"
+            dryrun = true
+            "#,
+        )
+        .unwrap();
+        let layer: ConfigLayer = toml::from_str(r#"profile = "generic-web""#).unwrap();
+        let config = layer.apply(&base).unwrap();
+        assert_eq!(config.supported_formats, ["mp4", "m4v"]);
+        assert_eq!(config.script, Some("plan.rhai".into()));
+        assert!(config.dryrun);
+    }
+
+    #[test]
+    fn unknown_profile() {
+        assert!(toml::from_str::<TranscoderConfig>(r#"profile = "vcr""#).is_err());
+    }
+}
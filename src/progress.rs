@@ -0,0 +1,126 @@
+use log::info;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::sync::{LazyLock, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use crate::probe;
+
+// Progress of running transcoding as reported by ffmpeg with -progress option. Transcoded part
+// is compared with duration of source to estimate the rest.
+#[derive(Debug, Clone, Serialize)]
+pub struct Progress {
+    pub src: PathBuf,
+    pub dst: PathBuf,
+    pub frames: u64,
+    // Transcoded part of the source
+    pub time: Duration,
+    // Relation of transcoding to playback speed
+    pub speed: Option<f64>,
+    pub duration: Option<Duration>,
+}
+
+const LOG_INTERVAL: Duration = Duration::from_secs(30);
+
+// Progress of running transcodings by destination
+static RUNNING: LazyLock<Mutex<HashMap<PathBuf, Progress>>> = LazyLock::new(Default::default);
+
+impl Progress {
+    // Progress of all running transcodings
+    pub fn running() -> Vec<Progress> {
+        Self::lock().values().cloned().collect()
+    }
+
+    pub fn percent(&self) -> Option<f64> {
+        let duration = self.duration?.as_secs_f64();
+        (duration > 0.0).then(|| (self.time.as_secs_f64() / duration * 100.0).min(100.0))
+    }
+
+    pub fn eta(&self) -> Option<Duration> {
+        let left = self.duration?.saturating_sub(self.time);
+        let speed = self.speed.filter(|speed| *speed > 0.0)?;
+        Some(Duration::from_secs_f64(left.as_secs_f64() / speed))
+    }
+
+    // Reads progress of ffmpeg from its output until it ends
+    pub(crate) fn track(src: &Path, dst: &Path, output: impl Read) {
+        let progress = Progress {
+            src: src.to_owned(),
+            dst: dst.to_owned(),
+            frames: 0,
+            time: Duration::ZERO,
+            speed: None,
+            duration: probe::duration(src),
+        };
+        Self::lock().insert(dst.to_owned(), progress);
+        let mut logged = Instant::now();
+        for line in BufReader::new(output).lines() {
+            let Ok(line) = line else {
+                break;
+            };
+            let Some((key, value)) = line.split_once('=') else {
+                continue;
+            };
+            let (key, value) = (key.trim(), value.trim());
+            let mut running = Self::lock();
+            let Some(progress) = running.get_mut(dst) else {
+                break;
+            };
+            progress.update(key, value);
+            // Values are reported in blocks, each ends with progress key
+            if key == "progress" && logged.elapsed() >= LOG_INTERVAL {
+                logged = Instant::now();
+                info!("{progress}");
+            }
+        }
+        Self::lock().remove(dst);
+    }
+
+    fn update(&mut self, key: &str, value: &str) {
+        match key {
+            "frame" => self.frames = value.parse().unwrap_or(self.frames),
+            // Despite the name, out_time_ms is in microseconds too
+            "out_time_us" | "out_time_ms" => {
+                if let Ok(us) = value.parse::<u64>() {
+                    self.time = Duration::from_micros(us);
+                }
+            }
+            "speed" => self.speed = value.trim_end_matches('x').trim().parse().ok(),
+            _ => (),
+        }
+    }
+
+    fn lock<'a>() -> MutexGuard<'a, HashMap<PathBuf, Progress>> {
+        RUNNING.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+impl std::fmt::Display for Progress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Transcoding {:?}: ", self.dst)?;
+        match self.percent() {
+            Some(percent) => write!(f, "{percent:.1}%")?,
+            None => write!(f, "{}", Hms(self.time))?,
+        }
+        write!(f, ", {} frames", self.frames)?;
+        if let Some(speed) = self.speed {
+            write!(f, " at {speed:.2}x")?;
+        }
+        if let Some(eta) = self.eta() {
+            write!(f, ", ETA {}", Hms(eta))?;
+        }
+        Ok(())
+    }
+}
+
+// Duration formatted as hours, minutes and seconds
+struct Hms(Duration);
+
+impl std::fmt::Display for Hms {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let secs = self.0.as_secs();
+        write!(f, "{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+    }
+}
@@ -0,0 +1,144 @@
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::SystemTime;
+use tokio::sync::Notify;
+
+use crate::retry::FailureKind;
+use crate::state::State;
+use crate::transcoder::{Transcoder, TranscoderConfig, remove_output};
+
+// Sources waiting to be placed into destinations. Jobs are stored in state and processed one by
+// one in order of queueing, so they survive restarts.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Job {
+    pub src: PathBuf,
+    // Source of the watch pair the job belongs to
+    pub root: PathBuf,
+    // Whether to place only outputs which are missed or outdated
+    pub check_exists: bool,
+    // Number of failed attempts
+    #[serde(default)]
+    pub attempt: u32,
+    // Failed job is retried not earlier than this time
+    #[serde(default)]
+    pub not_before: Option<SystemTime>,
+}
+
+pub struct Queue;
+
+static QUEUED: Notify = Notify::const_new();
+
+// Paused queue keeps jobs, but does not start them
+static PAUSED: AtomicBool = AtomicBool::new(false);
+
+impl Queue {
+    // Source already waiting in queue is not queued again
+    pub fn push(job: Job) {
+        if let Some(id) = State::push_job(&job) {
+            debug!("Queued job {id} for {:?}", job.src);
+            QUEUED.notify_one();
+        }
+    }
+
+    // Waits for the next job and marks it as running. Delayed jobs are taken when they are due
+    pub async fn next() -> (u64, Job) {
+        loop {
+            if PAUSED.load(Ordering::Relaxed) {
+                QUEUED.notified().await;
+                continue;
+            }
+            if let Some(job) = State::start_job() {
+                return job;
+            }
+            let delay = State::earliest_retry()
+                .map(|time| time.duration_since(SystemTime::now()).unwrap_or_default());
+            match delay {
+                Some(delay) => {
+                    tokio::select! {
+                        _ = QUEUED.notified() => (),
+                        _ = tokio::time::sleep(delay) => (),
+                    }
+                }
+                None => QUEUED.notified().await,
+            }
+        }
+    }
+
+    // Queued jobs with flag whether each is running
+    pub fn jobs() -> Vec<(u64, Job, bool)> {
+        State::jobs()
+    }
+
+    // Running job is not interrupted
+    pub fn pause() {
+        PAUSED.store(true, Ordering::Relaxed);
+    }
+
+    pub fn unpause() {
+        PAUSED.store(false, Ordering::Relaxed);
+        QUEUED.notify_one();
+    }
+
+    pub fn is_paused() -> bool {
+        PAUSED.load(Ordering::Relaxed)
+    }
+
+    // Removes queued job or cancels the running one. Running job can be cancelled only while its
+    // source is transcoded
+    pub fn cancel(id: u64) -> Result<Job, String> {
+        let (_, job, running) = State::jobs()
+            .into_iter()
+            .find(|(job_id, _, _)| *job_id == id)
+            .ok_or(format!("No job {id}"))?;
+        if !running {
+            State::finish_job(id);
+        } else if !Transcoder::cancel(&job.src) {
+            return Err(format!("Job {id} is not transcoding yet, try again later"));
+        }
+        Ok(job)
+    }
+
+    pub fn done(id: u64) {
+        State::finish_job(id);
+    }
+
+    // Delays the job for retry. Source is quarantined if failure is permanent or all attempts
+    // are used up
+    pub fn failed(id: u64, mut job: Job, kind: FailureKind) {
+        let retry = TranscoderConfig::get().retry.clone();
+        if kind.is_permanent() || job.attempt + 1 >= retry.attempts {
+            warn!(
+                "Quarantining {:?} after {} attempts: {kind:?}",
+                job.src,
+                job.attempt + 1
+            );
+            State::quarantine(&job.src, kind);
+            State::finish_job(id);
+            return;
+        }
+        let delay = retry.delay(job.attempt);
+        job.attempt += 1;
+        job.not_before = Some(SystemTime::now() + delay);
+        // Outputs placed by the failed attempt are kept
+        job.check_exists = true;
+        debug!("Retrying {:?} in {delay:?}", job.src);
+        State::delay_job(id, &job);
+    }
+
+    // Returns jobs interrupted by restart to queue and removes their partial outputs
+    pub fn resume() {
+        for output in State::take_partials() {
+            debug!("Removing partial output {output:?}");
+            if let Err(err) = remove_output(&output) {
+                warn!("Failed to delete {output:?}: {err:?}");
+            }
+        }
+        let count = State::requeue_jobs();
+        if count > 0 {
+            debug!("Resuming {count} queued jobs");
+            QUEUED.notify_one();
+        }
+    }
+}
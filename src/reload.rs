@@ -0,0 +1,81 @@
+use futures_util::StreamExt;
+use inotify::{EventMask, EventStream, Inotify, WatchMask};
+use log::{debug, trace, warn};
+use std::ffi::OsString;
+use std::path::{self, Path, PathBuf};
+use tokio::signal::unix::{Signal, SignalKind, signal};
+
+// Notifies when configuration should be reloaded: either on SIGHUP or when the configuration
+// file is rewritten. The directory of file is watched, as editors often replace the file instead
+// of writing to it. Files included by nix configuration are not tracked, SIGHUP covers them.
+pub struct ConfigWatcher {
+    path: PathBuf,
+    name: OsString,
+    // Raw events are read, as async_inotify panics on overflow, which happens in busy
+    // directories. Only SIGHUP is waited for after events failed to be read
+    events: Option<EventStream<[u8; 1024]>>,
+    hangup: Signal,
+}
+
+impl ConfigWatcher {
+    pub fn new(path: &Path) -> async_inotify::Result<Self> {
+        let path = path::absolute(path)?;
+        let dir = path
+            .parent()
+            .ok_or("Configuration has no parent directory")?;
+        let name = path
+            .file_name()
+            .ok_or("Configuration has no file name")?
+            .to_owned();
+        let inotify = Inotify::init()?;
+        // Creation is not watched, as the file is still empty then
+        inotify
+            .watches()
+            .add(dir, WatchMask::CLOSE_WRITE.union(WatchMask::MOVED_TO))?;
+        Ok(Self {
+            path,
+            name,
+            events: Some(inotify.into_event_stream([0; 1024])?),
+            hangup: signal(SignalKind::hangup())?,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // Waits for the next reason to reload configuration
+    pub async fn changed(&mut self) {
+        loop {
+            let event = async {
+                match self.events.as_mut() {
+                    Some(events) => events.next().await,
+                    None => std::future::pending().await,
+                }
+            };
+            tokio::select! {
+                _ = self.hangup.recv() => {
+                    debug!("Got SIGHUP");
+                    return;
+                }
+                event = event => match event {
+                    // Change of configuration may be among the lost events
+                    Some(Ok(event)) if event.mask.contains(EventMask::Q_OVERFLOW) => {
+                        debug!("Queue of inotify events overflowed");
+                        return;
+                    }
+                    Some(Ok(event)) if event.name.as_deref() == Some(&self.name) => {
+                        debug!("Configuration {:?} changed", self.path);
+                        return;
+                    }
+                    Some(Ok(event)) => trace!("Ignoring {:?}", event.name),
+                    Some(Err(err)) => {
+                        warn!("Failed to read inotify events, reloading only on SIGHUP: {err}");
+                        self.events = None;
+                    }
+                    None => self.events = None,
+                },
+            }
+        }
+    }
+}
@@ -0,0 +1,208 @@
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::process::ExitStatus;
+use std::time::Duration;
+
+use crate::probe::{self, ProbeKey};
+
+// Failed jobs are retried after backoff growing twice with each attempt. Files failed with
+// permanent errors or with all attempts used up are quarantined.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct RetryConfig {
+    pub attempts: u32,
+    // Delay before the first retry in seconds
+    pub backoff: u64,
+    #[serde(alias = "max-backoff")]
+    pub max_backoff: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum FailureKind {
+    // I/O errors, lack of space, killed ffmpeg and so on
+    Transient,
+    // Source could not be decoded
+    CorruptInput,
+    // Required encoder or muxer is not available
+    MissingEncoder,
+}
+
+// Failure of ffmpeg with the tail of its output
+#[derive(Debug)]
+pub struct FfmpegError {
+    pub status: ExitStatus,
+    pub stderr: String,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            attempts: 3,
+            backoff: 60,
+            max_backoff: 3600,
+        }
+    }
+}
+
+impl RetryConfig {
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let delay = self
+            .backoff
+            .saturating_mul(1u64.checked_shl(attempt).unwrap_or(u64::MAX));
+        Duration::from_secs(delay.min(self.max_backoff))
+    }
+}
+
+impl FailureKind {
+    pub fn of(err: &io::Error) -> Self {
+        let Some(err) = err
+            .get_ref()
+            .and_then(|err| err.downcast_ref::<FfmpegError>())
+        else {
+            return Self::Transient;
+        };
+        let stderr = err.stderr.to_lowercase();
+        if [
+            "unknown encoder",
+            "encoder not found",
+            "unable to find a suitable output format",
+            "could not find tag for codec",
+            "not currently supported in container",
+        ]
+        .iter()
+        .any(|msg| stderr.contains(msg))
+        {
+            Self::MissingEncoder
+        } else if [
+            "invalid data found when processing input",
+            "moov atom not found",
+            "error while decoding",
+            "corrupt decoded frame",
+            "packet corrupt",
+            "file ended prematurely",
+        ]
+        .iter()
+        .any(|msg| stderr.contains(msg))
+        {
+            Self::CorruptInput
+        } else {
+            Self::Transient
+        }
+    }
+
+    // Files still being written fail the same way as corrupt ones, so input is considered
+    // corrupt only if source stays the same since the job started and after probing it again
+    pub fn confirm(self, src: &Path, started: Option<ProbeKey>) -> Self {
+        if self != Self::CorruptInput {
+            return self;
+        }
+        probe::probe(src);
+        if started.is_some() && ProbeKey::of(src) == started {
+            self
+        } else {
+            debug!("Source {src:?} changed since the job started, it is not considered corrupt");
+            Self::Transient
+        }
+    }
+
+    // Permanent failures are not retried
+    pub fn is_permanent(self) -> bool {
+        self != Self::Transient
+    }
+}
+
+impl fmt::Display for FfmpegError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ffmpeg failed with {}", self.status)?;
+        if !self.stderr.is_empty() {
+            write!(f, ": {}", self.stderr.trim())?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for FfmpegError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::process::ExitStatusExt;
+
+    fn ffmpeg_failure(stderr: &str) -> FailureKind {
+        FailureKind::of(&io::Error::other(FfmpegError {
+            status: ExitStatus::from_raw(1 << 8),
+            stderr: stderr.to_string(),
+        }))
+    }
+
+    #[test]
+    fn backoff_grows_up_to_max() {
+        let retry = RetryConfig {
+            attempts: 10,
+            backoff: 5,
+            max_backoff: 60,
+        };
+        let delays: Vec<_> = (0..6)
+            .map(|attempt| retry.delay(attempt).as_secs())
+            .collect();
+        assert_eq!(delays, [5, 10, 20, 40, 60, 60]);
+        // Shift beyond the width of delay is capped too
+        assert_eq!(retry.delay(100), Duration::from_secs(60));
+    }
+
+    #[test]
+    fn failures_are_classified() {
+        assert_eq!(
+            FailureKind::of(&io::Error::from(io::ErrorKind::StorageFull)),
+            FailureKind::Transient
+        );
+        assert_eq!(ffmpeg_failure("Killed"), FailureKind::Transient);
+        // Truncated packets are reported for files still being written as well
+        assert_eq!(
+            ffmpeg_failure("[mp3 @ 0x1] Truncating packet of size 1024"),
+            FailureKind::Transient
+        );
+        assert_eq!(
+            ffmpeg_failure("Unknown encoder 'libfdk_aac'"),
+            FailureKind::MissingEncoder
+        );
+        assert_eq!(
+            ffmpeg_failure("Could not find tag for codec pcm_s16le in stream #1"),
+            FailureKind::MissingEncoder
+        );
+        assert_eq!(
+            ffmpeg_failure("input.mp4: Invalid data found when processing input"),
+            FailureKind::CorruptInput
+        );
+        assert_eq!(
+            ffmpeg_failure("[mov,mp4 @ 0x1] moov atom not found"),
+            FailureKind::CorruptInput
+        );
+        assert!(!FailureKind::Transient.is_permanent());
+        assert!(FailureKind::CorruptInput.is_permanent());
+        assert!(FailureKind::MissingEncoder.is_permanent());
+    }
+
+    #[test]
+    fn corrupt_input_is_confirmed_for_unchanged_source() {
+        let src = std::env::temp_dir().join(format!("transcoder-retry-{}", std::process::id()));
+        fs::write(&src, "not media").unwrap();
+        let started = ProbeKey::of(&src);
+        let kind = FailureKind::CorruptInput;
+        assert_eq!(kind.confirm(&src, started), FailureKind::CorruptInput);
+        assert_eq!(kind.confirm(&src, None), FailureKind::Transient);
+        assert_eq!(
+            FailureKind::MissingEncoder.confirm(&src, None),
+            FailureKind::MissingEncoder
+        );
+        // Source is still being written
+        fs::write(&src, "not media yet").unwrap();
+        assert_eq!(kind.confirm(&src, started), FailureKind::Transient);
+        fs::remove_file(&src).unwrap();
+        assert_eq!(kind.confirm(&src, started), FailureKind::Transient);
+    }
+}
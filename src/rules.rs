@@ -0,0 +1,352 @@
+use ez_ffmpeg::stream_info::StreamInfo;
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::fmt;
+
+use crate::transcoder::{
+    CodecInfoExtra, GetAVCodec, GetIndex, Requirement, RequirementLevel, RequirementType,
+    TranscoderConfig, deserialize_codec, serialize_codec,
+};
+
+// Rule is a requirement with explicit priority and optional condition. Rules with greater
+// priority are evaluated first. Plain requirements from config act like rules with zero
+// priority placed after explicit rules of the same priority.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Rule {
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub priority: i32,
+    #[serde(flatten)]
+    pub requirement: Requirement,
+    #[serde(default)]
+    pub when: Option<Condition>,
+}
+
+// Condition is evaluated for each stream matched by rule's requirement. The rule applies to
+// stream only if condition holds.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub enum Condition {
+    All(Vec<Condition>),
+    Any(Vec<Condition>),
+    Not(Box<Condition>),
+    // The stream under decision matches selector
+    Stream(StreamSelector),
+    // Any other stream of the same file matches selector
+    Exists(StreamSelector),
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct StreamSelector {
+    what: RequirementType,
+    #[serde(
+        default,
+        deserialize_with = "deserialize_codec",
+        serialize_with = "serialize_codec",
+        skip_serializing_if = "Option::is_none"
+    )]
+    codec: Option<CodecInfoExtra>,
+}
+
+// Borrowed view to either rule or plain requirement in order of evaluation
+#[derive(Debug, Clone, Copy)]
+pub struct Policy<'a> {
+    pub requirement: &'a Requirement,
+    pub name: Option<&'a str>,
+    pub priority: i32,
+    pub when: Option<&'a Condition>,
+}
+
+impl TranscoderConfig {
+    pub fn policies(&self) -> Vec<Policy<'_>> {
+        let mut policies: Vec<_> = self
+            .rules
+            .iter()
+            .map(|rule| Policy {
+                requirement: &rule.requirement,
+                name: rule.name.as_deref(),
+                priority: rule.priority,
+                when: rule.when.as_ref(),
+            })
+            .chain(self.required.iter().map(|requirement| Policy {
+                requirement,
+                name: None,
+                priority: 0,
+                when: None,
+            }))
+            .collect();
+        // Sort is stable, so rules remain before requirements of the same priority
+        policies.sort_by_key(|policy| Reverse(policy.priority));
+        policies
+    }
+}
+
+impl Policy<'_> {
+    pub fn get_level(&self) -> RequirementLevel {
+        self.requirement.level
+    }
+
+    pub fn applies(&self, stream: &StreamInfo, streams: &[StreamInfo]) -> bool {
+        *self.requirement == *stream
+            && self
+                .when
+                .map(|cond| cond.matches(stream, streams))
+                .unwrap_or(true)
+    }
+}
+
+impl Condition {
+    pub fn matches(&self, stream: &StreamInfo, streams: &[StreamInfo]) -> bool {
+        match self {
+            Self::All(conds) => conds.iter().all(|c| c.matches(stream, streams)),
+            Self::Any(conds) => conds.iter().any(|c| c.matches(stream, streams)),
+            Self::Not(cond) => !cond.matches(stream, streams),
+            Self::Stream(sel) => sel.matches(stream),
+            Self::Exists(sel) => streams
+                .iter()
+                .filter(|other| other.get_index() != stream.get_index())
+                .any(|other| sel.matches(other)),
+        }
+    }
+
+    // Whether the condition is false for each stream of requirement type. Only obvious
+    // contradictions are detected, negations are never considered contradictory
+    pub fn never_matches(&self, what: &RequirementType) -> bool {
+        match self {
+            Self::All(conds) => conds.iter().any(|c| c.never_matches(what)),
+            Self::Any(conds) => conds.iter().all(|c| c.never_matches(what)),
+            Self::Not(_) => false,
+            Self::Stream(sel) => !sel.what.overlaps(what) || sel.never_matches(),
+            Self::Exists(sel) => sel.never_matches(),
+        }
+    }
+}
+
+impl StreamSelector {
+    // Codec of another media type than selected streams never matches
+    fn never_matches(&self) -> bool {
+        self.codec
+            .as_ref()
+            .is_some_and(|codec| codec.media_type != self.what.media_type())
+    }
+
+    pub fn matches(&self, stream: &StreamInfo) -> bool {
+        self.what == *stream
+            && self
+                .codec
+                .as_ref()
+                .map(|codec| stream.get_avcodec() == Some(codec.codec_id))
+                .unwrap_or(true)
+    }
+}
+
+impl fmt::Display for Policy<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(name) = self.name {
+            write!(f, "rule {name:?}")
+        } else {
+            write!(
+                f,
+                "requirement {:?}/{:?}",
+                self.requirement.what, self.requirement.level
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transcoder::tests::{audio, subtitle, video};
+    use ffmpeg_sys_next::AVCodecID::*;
+
+    fn condition(text: &str) -> Condition {
+        toml::from_str::<Rule>(&format!(
+            "what = \"Video\"\nlevel = \"Drop\"\nwhen = {text}"
+        ))
+        .unwrap()
+        .when
+        .unwrap()
+    }
+
+    fn streams() -> Vec<StreamInfo> {
+        vec![
+            video(0, AV_CODEC_ID_HEVC),
+            audio(1, AV_CODEC_ID_AAC, Some("eng")),
+            audio(2, AV_CODEC_ID_AC3, Some("rus")),
+        ]
+    }
+
+    #[test]
+    fn stream_condition() {
+        let streams = streams();
+        let cond = condition(r#"{ Stream = { what = { Audio = { language = "rus" } } } }"#);
+        assert!(!cond.matches(&streams[1], &streams));
+        assert!(cond.matches(&streams[2], &streams));
+        let cond = condition(r#"{ Stream = { what = { Audio = {} }, codec = "aac" } }"#);
+        assert!(cond.matches(&streams[1], &streams));
+        assert!(!cond.matches(&streams[2], &streams));
+    }
+
+    #[test]
+    fn exists_condition_skips_stream_itself() {
+        let streams = streams();
+        let cond = condition(r#"{ Exists = { what = { Audio = { language = "eng" } } } }"#);
+        assert!(cond.matches(&streams[0], &streams));
+        assert!(cond.matches(&streams[2], &streams));
+        assert!(!cond.matches(&streams[1], &streams));
+        let cond = condition(r#"{ Exists = { what = { Subtitle = {} } } }"#);
+        assert!(!cond.matches(&streams[0], &streams));
+        let mut streams = streams;
+        streams.push(subtitle(3, AV_CODEC_ID_SUBRIP, None));
+        assert!(cond.matches(&streams[0], &streams));
+    }
+
+    #[test]
+    fn combined_conditions() {
+        let streams = streams();
+        let eng = r#"{ Stream = { what = { Audio = { language = "eng" } } } }"#;
+        let ac3 = r#"{ Exists = { what = { Audio = {} }, codec = "ac3" } }"#;
+        let all = condition(&format!("{{ All = [{eng}, {ac3}] }}"));
+        assert!(all.matches(&streams[1], &streams));
+        assert!(!all.matches(&streams[2], &streams));
+        let any = condition(&format!("{{ Any = [{eng}, {ac3}] }}"));
+        assert!(any.matches(&streams[0], &streams));
+        assert!(any.matches(&streams[1], &streams));
+        // The only ac3 stream is the stream itself
+        assert!(!any.matches(&streams[2], &streams));
+        let not = condition(&format!("{{ Not = {eng} }}"));
+        assert!(!not.matches(&streams[1], &streams));
+        assert!(not.matches(&streams[2], &streams));
+        assert!(condition("{ All = [] }").matches(&streams[0], &streams));
+        assert!(!condition("{ Any = [] }").matches(&streams[0], &streams));
+    }
+
+    #[test]
+    fn policies_are_ordered_by_priority() {
+        let config: TranscoderConfig = toml::from_str(
+            r#"
+            supported-formats = ["mkv"]
+            supported-codecs = ["libx264", "aac"]
+            requirements = [{ what = "Video", level = "All" }]
+
+            [[rules]]
+            name = "low"
+            priority = -1
+            what = "Video"
+            level = "Ignore"
+
+            [[rules]]
+            name = "same"
+            what = "Video"
+            level = "Ignore"
+
+            [[rules]]
+            name = "high"
+            priority = 5
+            what = "Video"
+            level = "Ignore"
+            "#,
+        )
+        .unwrap();
+        let policies: Vec<_> = config
+            .policies()
+            .iter()
+            .map(|policy| policy.to_string())
+            .collect();
+        // Rules go before requirements of the same priority
+        assert_eq!(
+            policies,
+            [
+                "rule \"high\"",
+                "rule \"same\"",
+                "requirement Video/All",
+                "rule \"low\""
+            ]
+        );
+    }
+
+    fn never_matches(rule: &str) -> bool {
+        let rule: Rule = toml::from_str(rule).unwrap();
+        rule.when.unwrap().never_matches(&rule.requirement.what)
+    }
+
+    #[test]
+    fn stream_of_another_type() {
+        assert!(never_matches(
+            r#"
+            what = "Video"
+            level = "Drop"
+            when = { Stream = { what = { Audio = {} } } }
+            "#
+        ));
+    }
+
+    #[test]
+    fn stream_of_another_language() {
+        assert!(never_matches(
+            r#"
+            what = { Audio = { language = "rus" } }
+            level = "Drop"
+            when = { Stream = { what = { Audio = { language = "eng" } } } }
+            "#
+        ));
+        assert!(!never_matches(
+            r#"
+            what = { Audio = {} }
+            level = "Drop"
+            when = { Stream = { what = { Audio = { language = "eng" } } } }
+            "#
+        ));
+    }
+
+    #[test]
+    fn codec_of_another_type() {
+        assert!(never_matches(
+            r#"
+            what = { Audio = {} }
+            level = "Drop"
+            when = { Exists = { what = "Video", codec = "aac" } }
+            "#
+        ));
+        assert!(!never_matches(
+            r#"
+            what = { Audio = {} }
+            level = "Drop"
+            when = { Exists = { what = "Video", codec = "libx264" } }
+            "#
+        ));
+    }
+
+    #[test]
+    fn combinations() {
+        assert!(never_matches(
+            r#"
+            what = "Video"
+            level = "Drop"
+            when = { All = [
+                { Exists = { what = { Audio = {} } } },
+                { Stream = { what = { Subtitle = {} } } },
+            ] }
+            "#
+        ));
+        assert!(!never_matches(
+            r#"
+            what = "Video"
+            level = "Drop"
+            when = { Any = [
+                { Stream = { what = { Subtitle = {} } } },
+                { Exists = { what = { Audio = {} } } },
+            ] }
+            "#
+        ));
+        // Negations are not analyzed
+        assert!(!never_matches(
+            r#"
+            what = "Video"
+            level = "Drop"
+            when = { Not = { Stream = { what = "Video" } } }
+            "#
+        ));
+    }
+}
@@ -0,0 +1,603 @@
+use serde_json::{Map, Value, json};
+use std::collections::BTreeSet;
+
+use crate::profiles;
+use crate::transcoder::IndexedCodecs;
+
+// JSON Schema of configuration file for editors and CI. It follows deserialization of
+// ConfigLayer, so both names of aliased keys are described. Unknown keys are ignored by
+// transcoder, but are reported by schema as they are likely typos.
+//
+// Codecs are any strings unless codecs of local ffmpeg build are requested. These are listed in
+// lower case.
+pub fn generate(local_codecs: bool) -> Value {
+    let codec = if local_codecs {
+        // Both names are accepted by IndexedCodecs in any case, but enum of schema is case
+        // sensitive, so names are listed in lower case
+        let names: BTreeSet<_> = IndexedCodecs::get()
+            .codecs()
+            .iter()
+            .flat_map(|codec| [codec.codec_name.clone(), codec.codec_long_name.clone()])
+            .map(|name| name.to_lowercase())
+            .collect();
+        json!({
+            "type": "string",
+            "enum": names,
+            "description": "Name or long name of codec in lower case",
+        })
+    } else {
+        json!({
+            "type": "string",
+            "description": "Name or long name of codec, case insensitive",
+        })
+    };
+    // Policies have to be given in full when no profile is referenced
+    let complete: Vec<_> = [
+        ("supported_formats", "supported-formats"),
+        ("supported_codecs", "supported-codecs"),
+        ("required", "requirements"),
+    ]
+    .iter()
+    .map(|(name, alias)| json!({ "anyOf": [{ "required": [name] }, { "required": [alias] }] }))
+    .collect();
+    json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": "transcoder configuration",
+        "type": "object",
+        "properties": layer(),
+        "additionalProperties": false,
+        "anyOf": [
+            { "required": ["profile"] },
+            { "allOf": complete },
+            // Each watch entry may reference own profile instead
+            {
+                "required": ["watch"],
+                "properties": {
+                    "watch": { "minItems": 1, "items": { "required": ["profile"] } },
+                },
+            },
+        ],
+        "$defs": {
+            "codec": codec,
+            "codecs": { "type": "array", "items": { "$ref": "#/$defs/codec" } },
+            "formats": {
+                "type": "array",
+                "items": { "type": "string" },
+                "description": "File extensions, case insensitive",
+            },
+            "requirement-type": {
+                "oneOf": [
+                    { "const": "Video" },
+                    {
+                        "type": "object",
+                        "properties": { "Audio": { "$ref": "#/$defs/language" } },
+                        "required": ["Audio"],
+                        "additionalProperties": false,
+                    },
+                    {
+                        "type": "object",
+                        "properties": { "Subtitle": { "$ref": "#/$defs/language" } },
+                        "required": ["Subtitle"],
+                        "additionalProperties": false,
+                    },
+                ],
+            },
+            "language": {
+                "type": "object",
+                "properties": {
+                    "language": {
+                        "type": ["string", "null"],
+                        "description": "Language from stream metadata, any if not set",
+                    },
+                },
+                "additionalProperties": false,
+            },
+            "requirement-level": {
+                "enum": ["All", "AtLeastOne", "WithOther", "Ignore", "Decline", "Drop"],
+            },
+            "requirement": {
+                "type": "object",
+                "properties": requirement(),
+                "required": ["what", "level"],
+                "additionalProperties": false,
+            },
+            "rule": {
+                "type": "object",
+                "properties": rule(),
+                "required": ["what", "level"],
+                "additionalProperties": false,
+            },
+            "conditions": { "type": "array", "items": { "$ref": "#/$defs/condition" } },
+            "condition": {
+                "oneOf": [
+                    variant("All", json!({ "$ref": "#/$defs/conditions" })),
+                    variant("Any", json!({ "$ref": "#/$defs/conditions" })),
+                    variant("Not", json!({ "$ref": "#/$defs/condition" })),
+                    variant("Stream", json!({ "$ref": "#/$defs/stream-selector" })),
+                    variant("Exists", json!({ "$ref": "#/$defs/stream-selector" })),
+                ],
+            },
+            "stream-selector": {
+                "type": "object",
+                "properties": {
+                    "what": { "$ref": "#/$defs/requirement-type" },
+                    "codec": { "anyOf": [{ "$ref": "#/$defs/codec" }, { "type": "null" }] },
+                },
+                "required": ["what"],
+                "additionalProperties": false,
+            },
+            "retry": {
+                "type": "object",
+                "properties": retry(),
+                "additionalProperties": false,
+            },
+            "output": {
+                "type": "object",
+                "properties": output(),
+                "required": ["dst"],
+                "additionalProperties": false,
+            },
+            "watch": {
+                "type": "object",
+                "properties": watch(),
+                "required": ["src", "dst"],
+                "additionalProperties": false,
+            },
+        },
+    })
+}
+
+fn layer() -> Map<String, Value> {
+    let mut props = Map::new();
+    props.insert(
+        "profile".into(),
+        json!({ "enum": profiles::names().collect::<Vec<_>>() }),
+    );
+    aliased(
+        &mut props,
+        "supported_formats",
+        "supported-formats",
+        json!({ "$ref": "#/$defs/formats" }),
+    );
+    aliased(
+        &mut props,
+        "extra_formats",
+        "extra-formats",
+        json!({ "$ref": "#/$defs/formats" }),
+    );
+    aliased(
+        &mut props,
+        "supported_codecs",
+        "supported-codecs",
+        json!({ "$ref": "#/$defs/codecs" }),
+    );
+    aliased(
+        &mut props,
+        "extra_codecs",
+        "extra-codecs",
+        json!({ "$ref": "#/$defs/codecs" }),
+    );
+    aliased(
+        &mut props,
+        "required",
+        "requirements",
+        json!({ "type": "array", "items": { "$ref": "#/$defs/requirement" } }),
+    );
+    props.insert(
+        "rules".into(),
+        json!({ "type": "array", "items": { "$ref": "#/$defs/rule" } }),
+    );
+    props.insert(
+        "script".into(),
+        json!({ "type": "string", "description": "Script altering plan of each file" }),
+    );
+    props.insert("dryrun".into(), json!({ "type": "boolean" }));
+    props.insert("retry".into(), json!({ "$ref": "#/$defs/retry" }));
+    aliased(
+        &mut props,
+        "hash_sources",
+        "hash-sources",
+        json!({ "type": "boolean", "description": "Detect replaced sources by content hash" }),
+    );
+    props.insert(
+        "state".into(),
+        json!({ "type": "string", "description": "Where the state of outputs is stored" }),
+    );
+    props.insert(
+        "socket".into(),
+        json!({ "type": "string", "description": "Where control requests are listened" }),
+    );
+    props.insert(
+        "metrics".into(),
+        json!({ "type": "string", "description": "Address of metrics endpoint, host:port. It has no authentication, so loopback address is preferred" }),
+    );
+    props.insert(
+        "events".into(),
+        json!({ "type": "string", "description": "Event log file, \"-\" for stdout" }),
+    );
+    props.insert(
+        "outputs".into(),
+        json!({ "type": "array", "items": { "$ref": "#/$defs/output" } }),
+    );
+    props.insert(
+        "watch".into(),
+        json!({ "type": "array", "items": { "$ref": "#/$defs/watch" } }),
+    );
+    props
+}
+
+fn requirement() -> Map<String, Value> {
+    let mut props = Map::new();
+    props.insert("what".into(), json!({ "$ref": "#/$defs/requirement-type" }));
+    props.insert(
+        "level".into(),
+        json!({ "$ref": "#/$defs/requirement-level" }),
+    );
+    props
+}
+
+fn rule() -> Map<String, Value> {
+    let mut props = requirement();
+    props.insert("name".into(), json!({ "type": "string" }));
+    props.insert(
+        "priority".into(),
+        json!({ "type": "integer", "description": "Rules with greater priority go first" }),
+    );
+    props.insert("when".into(), json!({ "$ref": "#/$defs/condition" }));
+    props
+}
+
+fn retry() -> Map<String, Value> {
+    let mut props = Map::new();
+    props.insert(
+        "attempts".into(),
+        json!({ "type": "integer", "minimum": 0 }),
+    );
+    props.insert(
+        "backoff".into(),
+        json!({
+            "type": "integer",
+            "minimum": 0,
+            "description": "Delay before the first retry in seconds",
+        }),
+    );
+    aliased(
+        &mut props,
+        "max_backoff",
+        "max-backoff",
+        json!({ "type": "integer", "minimum": 0 }),
+    );
+    props
+}
+
+fn output() -> Map<String, Value> {
+    let mut props = layer();
+    props.insert("dst".into(), json!({ "type": "string" }));
+    props
+}
+
+fn watch() -> Map<String, Value> {
+    let mut props = layer();
+    props.insert("src".into(), json!({ "type": "string" }));
+    props.insert(
+        "dst".into(),
+        json!({
+            "anyOf": [
+                { "type": "string" },
+                { "type": "array", "items": { "type": "string" } },
+            ],
+        }),
+    );
+    props.insert(
+        "recheck".into(),
+        json!({
+            "type": "boolean",
+            "default": true,
+            "description": "Check the whole source on start",
+        }),
+    );
+    props
+}
+
+// Key of configuration accepted under both names
+fn aliased(props: &mut Map<String, Value>, name: &str, alias: &str, schema: Value) {
+    props.insert(name.into(), schema.clone());
+    props.insert(alias.into(), schema);
+}
+
+// Externally tagged enum variant with data
+fn variant(name: &str, schema: Value) -> Value {
+    json!({
+        "type": "object",
+        "properties": { name: schema },
+        "required": [name],
+        "additionalProperties": false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+    use serde::de::{self, Visitor};
+
+    use crate::config::ConfigLayer;
+    use crate::retry::RetryConfig;
+    use crate::rules::StreamSelector;
+    use crate::transcoder::{Requirement, TranscoderConfig};
+
+    #[test]
+    fn shipped_schema_is_actual() {
+        let shipped: Value = serde_json::from_str(include_str!("../config.schema.json")).unwrap();
+        assert_eq!(shipped, generate(false));
+    }
+
+    // Keys of structures are the ones deserialization accepts, including aliases
+    #[test]
+    fn keys_are_described() {
+        let keys = |props: Map<String, Value>| props.keys().cloned().collect::<BTreeSet<_>>();
+        assert_eq!(fields::<ConfigLayer>(), keys(layer()));
+        assert_eq!(fields::<RetryConfig>(), keys(retry()));
+        assert_eq!(fields::<Requirement>(), keys(requirement()));
+        let schema = generate(false);
+        let selector = schema["$defs"]["stream-selector"]["properties"]
+            .as_object()
+            .unwrap();
+        assert_eq!(fields::<StreamSelector>(), keys(selector.clone()));
+    }
+
+    #[test]
+    fn profiles_match_schema() {
+        let schema = generate(true);
+        for name in profiles::names() {
+            let profile: Value = toml::from_str(profiles::get(name).unwrap()).unwrap();
+            check(&schema, &schema, &profile, name).unwrap();
+        }
+    }
+
+    #[test]
+    fn configs_match_schema() {
+        let schema = generate(true);
+        for config in [
+            r#"
+            profile = "generic-web"
+            extra-formats = ["mkv"]
+            extra-codecs = ["ac3"]
+            requirements = [{ what = { Audio = { language = "rus" } }, level = "All" }]
+            script = "plan.rhai"
+            dryrun = false
+            retry = { attempts = 3, backoff = 30, max-backoff = 600 }
+            hash-sources = true
+            state = "/var/lib/transcoder/state.redb"
+            socket = "/run/transcoder.sock"
+            metrics = "127.0.0.1:9464"
+            events = "-"
+            outputs = [{ dst = "/srv/tv", profile = "lg-webos" }]
+            watch = [
+                { src = "/media", dst = ["/srv/web", "/srv/tv"] },
+                { src = "/music", dst = "/srv/music", profile = "audio-only-opus", recheck = false },
+            ]
+
+            [[rules]]
+            name = "drop english when russian exists"
+            priority = 10
+            what = { Audio = { language = "eng" } }
+            level = "Drop"
+            when = { All = [
+                { Exists = { what = { Audio = { language = "rus" } } } },
+                { Not = { Stream = { what = { Audio = {} }, codec = "ac3" } } },
+            ] }
+            "#,
+            r#"
+            supported_formats = ["mp4"]
+            supported_codecs = ["libx264", "AAC"]
+            required = [
+                { what = "Video", level = "AtLeastOne" },
+                { what = { Subtitle = {} }, level = "Ignore" },
+            ]
+            "#,
+            r#"
+            watch = [{ src = "/media", dst = "/srv/web", profile = "generic-web" }]
+            "#,
+        ] {
+            let _: TranscoderConfig = toml::from_str(config).unwrap();
+            let mut value: Value = toml::from_str(config).unwrap();
+            // Codecs are lower case in schema, but in any case in configuration
+            lowercase_codecs(&mut value);
+            check(&schema, &schema, &value, "config").unwrap();
+        }
+    }
+
+    #[test]
+    fn schema_rejects_invalid_configs() {
+        let schema = generate(false);
+        for config in [
+            r#"supported-formats = ["mp4"]"#,
+            r#"profile = "vcr""#,
+            r#"
+            profile = "roku"
+            requirement = [{ what = "Video", level = "All" }]
+            "#,
+            r#"
+            profile = "roku"
+            requirements = [{ what = "Audio", level = "All" }]
+            "#,
+            r#"
+            profile = "roku"
+            retry = { attempts = -1 }
+            "#,
+            r#"watch = [{ src = "/media", dst = "/srv/web" }]"#,
+        ] {
+            let value: Value = toml::from_str(config).unwrap();
+            assert!(
+                check(&schema, &schema, &value, "config").is_err(),
+                "{config}"
+            );
+        }
+    }
+
+    fn lowercase_codecs(value: &mut Value) {
+        match value {
+            Value::Object(map) => {
+                for (key, value) in map.iter_mut() {
+                    match (key.as_str(), value) {
+                        ("codec", Value::String(codec)) => *codec = codec.to_lowercase(),
+                        (
+                            "supported-codecs" | "supported_codecs" | "extra-codecs"
+                            | "extra_codecs",
+                            Value::Array(codecs),
+                        ) => {
+                            for codec in codecs.iter_mut() {
+                                if let Value::String(codec) = codec {
+                                    *codec = codec.to_lowercase();
+                                }
+                            }
+                        }
+                        (_, value) => lowercase_codecs(value),
+                    }
+                }
+            }
+            Value::Array(values) => values.iter_mut().for_each(lowercase_codecs),
+            _ => (),
+        }
+    }
+
+    // Validation by the subset of JSON Schema which is generated
+    fn check(schema: &Value, root: &Value, value: &Value, path: &str) -> Result<(), String> {
+        if let Some(reference) = schema.get("$ref").and_then(Value::as_str) {
+            let name = reference.strip_prefix("#/$defs/").unwrap();
+            return check(&root["$defs"][name], root, value, path);
+        }
+        if let Some(types) = schema.get("type") {
+            let types: Vec<_> = match types {
+                Value::Array(types) => types.iter().filter_map(Value::as_str).collect(),
+                kind => kind.as_str().into_iter().collect(),
+            };
+            let matches = |kind: &str| match kind {
+                "object" => value.is_object(),
+                "array" => value.is_array(),
+                "string" => value.is_string(),
+                "boolean" => value.is_boolean(),
+                "integer" => value.is_i64() || value.is_u64(),
+                "null" => value.is_null(),
+                kind => panic!("Unexpected type {kind}"),
+            };
+            if !types.into_iter().any(matches) {
+                return Err(format!("{path}: {value} is not of type {}", schema["type"]));
+            }
+        }
+        if let Some(values) = schema.get("enum").and_then(Value::as_array)
+            && !values.contains(value)
+        {
+            return Err(format!("{path}: {value} is not one of {values:?}"));
+        }
+        if let Some(expected) = schema.get("const")
+            && expected != value
+        {
+            return Err(format!("{path}: {value} is not {expected}"));
+        }
+        if let Some(minimum) = schema.get("minimum").and_then(Value::as_i64)
+            && value.as_i64().is_some_and(|value| value < minimum)
+        {
+            return Err(format!("{path}: {value} is less than {minimum}"));
+        }
+        if let Value::Object(map) = value {
+            let props = schema.get("properties").and_then(Value::as_object);
+            for (key, value) in map {
+                match props.and_then(|props| props.get(key)) {
+                    Some(prop) => check(prop, root, value, &format!("{path}.{key}"))?,
+                    None if schema.get("additionalProperties") == Some(&Value::Bool(false)) => {
+                        return Err(format!("{path}: unknown key {key}"));
+                    }
+                    None => (),
+                }
+            }
+            for key in schema
+                .get("required")
+                .and_then(Value::as_array)
+                .into_iter()
+                .flatten()
+                .filter_map(Value::as_str)
+            {
+                if !map.contains_key(key) {
+                    return Err(format!("{path}: missing key {key}"));
+                }
+            }
+        }
+        if let Value::Array(values) = value {
+            if let Some(min) = schema.get("minItems").and_then(Value::as_u64)
+                && (values.len() as u64) < min
+            {
+                return Err(format!("{path}: less than {min} items"));
+            }
+            if let Some(items) = schema.get("items") {
+                for (i, value) in values.iter().enumerate() {
+                    check(items, root, value, &format!("{path}[{i}]"))?;
+                }
+            }
+        }
+        let results = |key: &str| {
+            schema.get(key).and_then(Value::as_array).map(|schemas| {
+                schemas
+                    .iter()
+                    .map(|schema| check(schema, root, value, path))
+                    .collect::<Vec<_>>()
+            })
+        };
+        if let Some(results) = results("allOf") {
+            results.into_iter().collect::<Result<(), _>>()?;
+        }
+        if let Some(results) = results("anyOf")
+            && !results.iter().any(Result::is_ok)
+        {
+            return Err(format!("{path}: no alternative matches: {results:?}"));
+        }
+        if let Some(results) = results("oneOf")
+            && results.iter().filter(|res| res.is_ok()).count() != 1
+        {
+            return Err(format!(
+                "{path}: not exactly one alternative matches: {results:?}"
+            ));
+        }
+        Ok(())
+    }
+
+    // Names of fields which structure is deserialized from
+    fn fields<'de, T: Deserialize<'de>>() -> BTreeSet<String> {
+        let mut fields = BTreeSet::new();
+        let _ = T::deserialize(Fields(&mut fields));
+        assert!(
+            !fields.is_empty(),
+            "{} is not a struct",
+            std::any::type_name::<T>()
+        );
+        fields
+    }
+
+    // Deserializer which only records fields of requested structure
+    struct Fields<'a>(&'a mut BTreeSet<String>);
+
+    impl<'de> de::Deserializer<'de> for Fields<'_> {
+        type Error = de::value::Error;
+
+        fn deserialize_any<V: Visitor<'de>>(self, _: V) -> Result<V::Value, Self::Error> {
+            Err(de::Error::custom("not a struct"))
+        }
+
+        fn deserialize_struct<V: Visitor<'de>>(
+            self,
+            _: &'static str,
+            fields: &'static [&'static str],
+            _: V,
+        ) -> Result<V::Value, Self::Error> {
+            self.0.extend(fields.iter().map(|field| field.to_string()));
+            Err(de::Error::custom("fields are recorded"))
+        }
+
+        serde::forward_to_deserialize_any! {
+            bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf
+            option unit unit_struct newtype_struct seq tuple tuple_struct map enum identifier
+            ignored_any
+        }
+    }
+}
@@ -0,0 +1,365 @@
+use ez_ffmpeg::stream_info::StreamInfo;
+use rhai::{AST, Array, Dynamic, Engine, Map, Scope};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::SystemTime;
+
+use crate::transcoder::{GetIndex, IndexedCodecs, TranscodeTaskType};
+
+// Plan of file processing passed to user script and returned back by it.
+//
+// Script is evaluated with next variables in scope:
+//   * `path` - path to the source file;
+//   * `streams` - array of probed streams, each is map with `index`, `type`, `codec`, `language`,
+//     `duration` (seconds), `metadata` and type-specific fields;
+//   * `plan` - default plan: `#{ transcode: bool, streams: [#{ index, action, codec }] }`, where
+//     `action` is one of "keep", "copy", "transcode" and "drop", and `codec` is a target codec
+//     for "transcode".
+// Script should either modify `plan` in place or evaluate to a new plan map. Action "keep" leaves
+// the default decision for stream. File with streams changed to "drop" or "transcode" is
+// transcoded even if `transcode` is false.
+#[derive(Debug)]
+pub(crate) struct Plan {
+    pub transcode: bool,
+    pub streams: HashMap<i32, TranscodeTaskType>,
+}
+
+// Script runs for every file, so runaway one should fail instead of stalling processing
+static ENGINE: LazyLock<Engine> = LazyLock::new(|| {
+    let mut engine = Engine::new();
+    engine
+        .set_max_operations(1_000_000)
+        .set_max_call_levels(32)
+        .set_max_expr_depths(64, 32)
+        .set_max_string_size(1 << 20)
+        .set_max_array_size(10_000)
+        .set_max_map_size(10_000)
+        .set_max_modules(0);
+    engine
+});
+
+// Compiled script with modification time of its file
+type Compiled = (SystemTime, Arc<AST>);
+
+static COMPILED: LazyLock<Mutex<HashMap<PathBuf, Compiled>>> = LazyLock::new(Default::default);
+
+pub(crate) fn run(
+    script: &Path,
+    src: &Path,
+    streams: &[StreamInfo],
+    default: Plan,
+) -> Result<Plan, String> {
+    let ast = compile(script)?;
+
+    let mut scope = Scope::new();
+    scope.push_constant("path", src.to_string_lossy().to_string());
+    scope.push_constant(
+        "streams",
+        streams.iter().map(stream_to_dynamic).collect::<Array>(),
+    );
+    scope.push("plan", plan_to_dynamic(&default));
+
+    let result: Dynamic = ENGINE
+        .eval_ast_with_scope(&mut scope, &ast)
+        .map_err(|err| err.to_string())?;
+    let plan = if result.is_map() {
+        result
+    } else {
+        scope
+            .get_value::<Dynamic>("plan")
+            .ok_or("Script removed the plan")?
+    };
+    plan_from_dynamic(plan, default)
+}
+
+// Script is compiled again only when its file is modified
+fn compile(script: &Path) -> Result<Arc<AST>, String> {
+    let modified = script
+        .metadata()
+        .and_then(|meta| meta.modified())
+        .map_err(|err| format!("Failed to read {script:?}: {err}"))?;
+    if let Some((time, ast)) = COMPILED.lock().unwrap().get(script)
+        && *time == modified
+    {
+        return Ok(ast.clone());
+    }
+    let ast = Arc::new(
+        ENGINE
+            .compile_file(script.to_owned())
+            .map_err(|err| err.to_string())?,
+    );
+    COMPILED
+        .lock()
+        .unwrap()
+        .insert(script.to_owned(), (modified, ast.clone()));
+    Ok(ast)
+}
+
+fn stream_to_dynamic(stream: &StreamInfo) -> Dynamic {
+    let mut map = Map::new();
+    map.insert("index".into(), (stream.get_index() as i64).into());
+    map.insert("type".into(), stream.stream_type().into());
+    let (metadata, codec_name, duration) = match stream {
+        StreamInfo::Video {
+            metadata,
+            codec_name,
+            duration,
+            time_base,
+            width,
+            height,
+            fps,
+            bit_rate,
+            ..
+        } => {
+            map.insert("width".into(), (*width as i64).into());
+            map.insert("height".into(), (*height as i64).into());
+            map.insert("fps".into(), (*fps).into());
+            map.insert("bit_rate".into(), (*bit_rate).into());
+            (metadata, Some(codec_name), seconds(*duration, time_base))
+        }
+        StreamInfo::Audio {
+            metadata,
+            codec_name,
+            duration,
+            time_base,
+            nb_channels,
+            sample_rate,
+            bit_rate,
+            ..
+        } => {
+            map.insert("channels".into(), (*nb_channels as i64).into());
+            map.insert("sample_rate".into(), (*sample_rate as i64).into());
+            map.insert("bit_rate".into(), (*bit_rate).into());
+            (metadata, Some(codec_name), seconds(*duration, time_base))
+        }
+        StreamInfo::Subtitle {
+            metadata,
+            codec_name,
+            duration,
+            time_base,
+            ..
+        } => (metadata, Some(codec_name), seconds(*duration, time_base)),
+        StreamInfo::Data {
+            metadata,
+            duration,
+            time_base,
+            ..
+        } => (metadata, None, seconds(*duration, time_base)),
+        StreamInfo::Attachment {
+            metadata,
+            codec_name,
+            ..
+        } => (metadata, Some(codec_name), None),
+        StreamInfo::Unknown { metadata, .. } => (metadata, None, None),
+    };
+    map.insert(
+        "codec".into(),
+        codec_name.map_or(Dynamic::UNIT, |c| c.clone().into()),
+    );
+    map.insert(
+        "language".into(),
+        metadata
+            .get("language")
+            .map_or(Dynamic::UNIT, |l| l.clone().into()),
+    );
+    map.insert(
+        "duration".into(),
+        duration.map_or(Dynamic::UNIT, Dynamic::from_float),
+    );
+    map.insert(
+        "metadata".into(),
+        metadata
+            .iter()
+            .map(|(k, v)| (k.into(), v.clone().into()))
+            .collect::<Map>()
+            .into(),
+    );
+    map.into()
+}
+
+fn seconds(duration: i64, time_base: &ez_ffmpeg::AVRational) -> Option<f64> {
+    if duration <= 0 || time_base.den == 0 {
+        None
+    } else {
+        Some(duration as f64 * time_base.num as f64 / time_base.den as f64)
+    }
+}
+
+fn plan_to_dynamic(plan: &Plan) -> Dynamic {
+    let mut indexes: Vec<_> = plan.streams.keys().copied().collect();
+    indexes.sort();
+    let streams: Array = indexes
+        .into_iter()
+        .map(|index| {
+            let mut map = Map::new();
+            map.insert("index".into(), (index as i64).into());
+            match &plan.streams[&index] {
+                TranscodeTaskType::Supported => {
+                    map.insert("action".into(), "copy".into());
+                }
+                TranscodeTaskType::Transcode(codec) => {
+                    map.insert("action".into(), "transcode".into());
+                    map.insert("codec".into(), codec.codec_name.clone().into());
+                }
+                TranscodeTaskType::Drop => {
+                    map.insert("action".into(), "drop".into());
+                }
+            }
+            Dynamic::from_map(map)
+        })
+        .collect();
+    let mut map = Map::new();
+    map.insert("transcode".into(), plan.transcode.into());
+    map.insert("streams".into(), streams.into());
+    map.into()
+}
+
+fn plan_from_dynamic(plan: Dynamic, default: Plan) -> Result<Plan, String> {
+    let mut plan = plan
+        .try_cast::<Map>()
+        .ok_or("Plan should be an object map")?;
+    let transcode = match plan.remove("transcode") {
+        Some(transcode) => transcode.as_bool()?,
+        None => default.transcode,
+    };
+    let mut streams = HashMap::new();
+    if let Some(entries) = plan.remove("streams") {
+        for entry in entries.into_typed_array::<Map>()? {
+            let index = entry
+                .get("index")
+                .ok_or("Stream plan has no index")?
+                .as_int()? as i32;
+            let action = entry
+                .get("action")
+                .map(|a| a.clone().into_string())
+                .transpose()?
+                .unwrap_or_else(|| "keep".to_string());
+            let default_task = default.streams.get(&index).cloned();
+            let task = match action.as_str() {
+                "keep" => default_task.unwrap_or(TranscodeTaskType::Supported),
+                "copy" => TranscodeTaskType::Supported,
+                "drop" => TranscodeTaskType::Drop,
+                "transcode" => match entry.get("codec") {
+                    Some(codec) => {
+                        let name = codec.clone().into_string()?;
+                        let codec = IndexedCodecs::find(&name)
+                            .filter(|codec| codec.encoder)
+                            .ok_or_else(|| format!("No encoder for codec {name}"))?;
+                        TranscodeTaskType::Transcode(codec)
+                    }
+                    None => match default_task {
+                        Some(task @ TranscodeTaskType::Transcode(_)) => task,
+                        _ => Err(format!("No codec to transcode stream {index} to"))?,
+                    },
+                },
+                action => Err(format!("Unknown action {action} for stream {index}"))?,
+            };
+            streams.insert(index, task);
+        }
+    }
+    for (index, task) in default.streams.into_iter() {
+        streams.entry(index).or_insert(task);
+    }
+    Ok(Plan { transcode, streams })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transcoder::tests::{audio, video};
+    use ffmpeg_sys_next::AVCodecID::*;
+    use std::fs;
+
+    // Runs script on a file with HEVC video planned to be transcoded and AAC audio to be copied
+    fn run_script(name: &str, text: &str) -> Result<Plan, String> {
+        let script =
+            std::env::temp_dir().join(format!("transcoder-{name}-{}.rhai", std::process::id()));
+        fs::write(&script, text).unwrap();
+        let streams = [video(0, AV_CODEC_ID_HEVC), audio(1, AV_CODEC_ID_AAC, None)];
+        let default = Plan {
+            transcode: true,
+            streams: HashMap::from([
+                (
+                    0,
+                    TranscodeTaskType::Transcode(IndexedCodecs::find("libx264").unwrap()),
+                ),
+                (1, TranscodeTaskType::Supported),
+            ]),
+        };
+        let res = run(&script, Path::new("movie.mkv"), &streams, default);
+        fs::remove_file(&script).unwrap();
+        res
+    }
+
+    #[test]
+    fn script_returns_plan() {
+        let plan = run_script(
+            "plan",
+            r#"
+            let changes = [];
+            for stream in streams {
+                if stream.type == "Audio" && stream.codec == "aac" {
+                    changes.push(#{ index: stream.index, action: "transcode", codec: "libopus" });
+                }
+            }
+            #{ streams: changes }
+            "#,
+        )
+        .unwrap();
+        assert!(plan.transcode);
+        assert!(
+            matches!(&plan.streams[&1], TranscodeTaskType::Transcode(codec) if codec.codec_name == "libopus")
+        );
+        // Streams not mentioned keep their default tasks
+        assert!(matches!(plan.streams[&0], TranscodeTaskType::Transcode(_)));
+    }
+
+    #[test]
+    fn script_declines() {
+        let plan = run_script(
+            "decline",
+            r#"
+            plan.transcode = false;
+            plan.streams = [#{ index: 0, action: "copy" }];
+            "#,
+        )
+        .unwrap();
+        assert!(!plan.transcode);
+        assert!(plan.streams[&0] == TranscodeTaskType::Supported);
+        assert!(plan.streams[&1] == TranscodeTaskType::Supported);
+    }
+
+    #[test]
+    fn malformed_plans_are_errors() {
+        for (name, text, err) in [
+            ("not-map", "plan = 42;", "Plan should be an object map"),
+            (
+                "no-index",
+                r#"#{ streams: [#{ action: "drop" }] }"#,
+                "Stream plan has no index",
+            ),
+            (
+                "action",
+                r#"#{ streams: [#{ index: 0, action: "mux" }] }"#,
+                "Unknown action mux for stream 0",
+            ),
+            (
+                "codec",
+                r#"#{ streams: [#{ index: 1, action: "transcode" }] }"#,
+                "No codec to transcode stream 1 to",
+            ),
+            (
+                "encoder",
+                r#"#{ streams: [#{ index: 1, action: "transcode", codec: "truehd" }] }"#,
+                "No encoder for codec truehd",
+            ),
+        ] {
+            assert_eq!(run_script(name, text).unwrap_err(), err, "{name}");
+        }
+        // Values of wrong types are reported by rhai
+        assert!(run_script("transcode", r#"#{ transcode: "yes" }"#).is_err());
+        assert!(run_script("streams", "#{ streams: 1 }").is_err());
+        assert!(run_script("throw", r#"throw "broken";"#).is_err());
+    }
+}
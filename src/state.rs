@@ -0,0 +1,706 @@
+use log::warn;
+use redb::backends::InMemoryBackend;
+use redb::{
+    Database, DatabaseError, Key, ReadOnlyDatabase, ReadTransaction, ReadableDatabase,
+    ReadableTable, TableDefinition, TableError, TableHandle, Value, WriteTransaction,
+};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::{LazyLock, RwLock, RwLockReadGuard};
+use std::time::SystemTime;
+
+use crate::probe::{Probe, ProbeKey};
+use crate::queue::Job;
+use crate::retry::FailureKind;
+use crate::transcoder::TranscoderConfig;
+
+// Persistent state of transcoder: processed sources and outputs produced from them. Outputs are
+// keyed by destination path as it mirrors the source, while actual output may have another
+// extension. Records are stored as JSON to keep them readable by external tools.
+pub struct State;
+
+type Table = TableDefinition<'static, &'static [u8], &'static [u8]>;
+
+const OUTPUTS: Table = TableDefinition::new("outputs");
+const SOURCES: Table = TableDefinition::new("sources");
+const PROBES: Table = TableDefinition::new("probes");
+// Key of the last probe of each path, to drop it when the file changes
+const PROBED: Table = TableDefinition::new("probed");
+const PARTIALS: Table = TableDefinition::new("partials");
+const JOBS: TableDefinition<u64, &[u8]> = TableDefinition::new("jobs");
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OutputRecord {
+    pub src: PathBuf,
+    // Actual path of the output
+    pub output: PathBuf,
+    // Fingerprint of configuration the output was planned with. Output is not replanned while
+    // configuration is the same
+    pub config: String,
+    // Description of the plan the output was built with
+    pub plan: String,
+    // Identity of source the output was built from. Records made without it are never compared
+    #[serde(default)]
+    pub source: Option<SourceIdentity>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SourceIdentity {
+    pub size: u64,
+    pub mtime: SystemTime,
+    // Hash of content is computed only when enabled in configuration
+    #[serde(default)]
+    pub hash: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SourceRecord {
+    pub identity: SourceIdentity,
+    pub status: SourceStatus,
+    // Errors of the last processing, one per failed destination
+    #[serde(default)]
+    pub errors: Vec<String>,
+    // Key of cached probe to drop it along with the record
+    #[serde(default)]
+    pub probe: Option<ProbeKey>,
+    // Kind of failure the source was quarantined for
+    #[serde(default)]
+    pub failure: Option<FailureKind>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SourceStatus {
+    Done,
+    Failed,
+    // Source is not processed until it changes or quarantine is cleared
+    Quarantined,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct StoredJob {
+    #[serde(flatten)]
+    job: Job,
+    running: bool,
+}
+
+// State is kept in memory until opened
+static DB: LazyLock<RwLock<Database>> = LazyLock::new(|| {
+    let db = Database::builder()
+        .create_with_backend(InMemoryBackend::new())
+        .map_err(redb::Error::from)
+        .and_then(State::init)
+        .expect("Failed to create in-memory state");
+    RwLock::new(db)
+});
+
+impl State {
+    pub fn open(path: &Path) -> Result<(), String> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(|err| err.to_string())?;
+        }
+        let db = Database::create(path)
+            .map_err(Self::open_error)
+            .and_then(|db| Self::init(db).map_err(|err| err.to_string()))?;
+        *DB.write().unwrap() = db;
+        Ok(())
+    }
+
+    // Dry runs work on a copy of state, so they never change it. State locked by the running
+    // transcoder cannot be read, so dry run starts from empty state then
+    pub fn open_copy(path: &Path) -> Result<(), String> {
+        if !path.exists() {
+            return Ok(());
+        }
+        match ReadOnlyDatabase::open(path) {
+            Ok(source) => Self::copy_from(&source),
+            Err(DatabaseError::DatabaseAlreadyOpen) => {
+                warn!("State {path:?} is used by running transcoder, continuing without it");
+                Ok(())
+            }
+            // State of transcoder which was not stopped cleanly has to be repaired first, which
+            // keeps its records
+            Err(DatabaseError::RepairAborted) => {
+                Self::copy_from(&Database::create(path).map_err(Self::open_error)?)
+            }
+            Err(err) => Err(Self::open_error(err)),
+        }
+    }
+
+    fn copy_from(source: &impl ReadableDatabase) -> Result<(), String> {
+        let res = (|| -> Result<(), redb::Error> {
+            let from = source.begin_read()?;
+            let db = Self::db();
+            let to = db.begin_write()?;
+            for table in [OUTPUTS, SOURCES, PROBES, PROBED, PARTIALS] {
+                Self::copy(&from, &to, table)?;
+            }
+            Self::copy(&from, &to, JOBS)?;
+            to.commit()?;
+            Ok(())
+        })();
+        res.map_err(|err| err.to_string())
+    }
+
+    fn open_error(err: DatabaseError) -> String {
+        match err {
+            DatabaseError::DatabaseAlreadyOpen => {
+                "it is used by another running transcoder".to_string()
+            }
+            err => err.to_string(),
+        }
+    }
+
+    // Tables missing in state made by older versions are skipped
+    fn copy<K: Key + 'static, V: Value + 'static>(
+        from: &ReadTransaction,
+        to: &WriteTransaction,
+        definition: TableDefinition<K, V>,
+    ) -> Result<(), redb::Error> {
+        let source = match from.open_table(definition) {
+            Ok(source) => source,
+            Err(TableError::TableDoesNotExist(_)) => return Ok(()),
+            Err(err) => return Err(err.into()),
+        };
+        let mut table = to.open_table(definition)?;
+        for entry in source.iter()? {
+            let (key, value) = entry?;
+            table.insert(key.value(), value.value())?;
+        }
+        Ok(())
+    }
+
+    // Default location is transcoder/state.redb inside XDG state directory
+    pub fn default_path() -> PathBuf {
+        std::env::var_os("XDG_STATE_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".local/state")))
+            .unwrap_or_default()
+            .join("transcoder/state.redb")
+    }
+
+    pub fn output(dst: &Path) -> Option<OutputRecord> {
+        Self::get(OUTPUTS, dst.as_os_str().as_bytes())
+    }
+
+    pub fn record(dst: &Path, record: OutputRecord) {
+        Self::put(OUTPUTS, dst.as_os_str().as_bytes(), Some(&record));
+    }
+
+    // Records of outputs placed under the destination root
+    pub fn outputs_under(root: &Path) -> Vec<(PathBuf, OutputRecord)> {
+        let res = (|| -> Result<_, redb::Error> {
+            let db = Self::db();
+            let txn = db.begin_read()?;
+            let table = txn.open_table(OUTPUTS)?;
+            let mut outputs = vec![];
+            for entry in table.range(root.as_os_str().as_bytes()..)? {
+                let (dst, record) = entry?;
+                let dst = Path::new(OsStr::from_bytes(dst.value()));
+                if !dst.starts_with(root) {
+                    // Keys are ordered bytewise, so entries of root may be followed only by ones
+                    // sharing the same prefix, like "root.other"
+                    if !dst
+                        .as_os_str()
+                        .as_bytes()
+                        .starts_with(root.as_os_str().as_bytes())
+                    {
+                        break;
+                    }
+                    continue;
+                }
+                if let Ok(record) = serde_json::from_slice(record.value()) {
+                    outputs.push((dst.to_owned(), record));
+                }
+            }
+            Ok(outputs)
+        })();
+        res.unwrap_or_else(|err| {
+            warn!("Failed to read state: {err}");
+            vec![]
+        })
+    }
+
+    pub fn forget(dst: &Path) -> Option<OutputRecord> {
+        Self::put(OUTPUTS, dst.as_os_str().as_bytes(), None)
+    }
+
+    pub fn source(src: &Path) -> Option<SourceRecord> {
+        Self::get(SOURCES, src.as_os_str().as_bytes())
+    }
+
+    pub fn record_source(src: &Path, record: SourceRecord) {
+        let probe = record.probe;
+        let previous = Self::put(SOURCES, src.as_os_str().as_bytes(), Some(&record));
+        if let Some(previous) = previous.and_then(|previous| previous.probe)
+            && Some(previous) != probe
+        {
+            Self::put::<Probe>(PROBES, &previous.to_bytes(), None);
+        }
+    }
+
+    // Forgets the source with its cached probe
+    pub fn forget_source(src: &Path) -> Option<SourceRecord> {
+        let record: Option<SourceRecord> = Self::put(SOURCES, src.as_os_str().as_bytes(), None);
+        Self::put::<ProbeKey>(PROBED, src.as_os_str().as_bytes(), None);
+        if let Some(probe) = record.as_ref().and_then(|record| record.probe) {
+            Self::put::<Probe>(PROBES, &probe.to_bytes(), None);
+        }
+        record
+    }
+
+    pub fn quarantine(src: &Path, kind: FailureKind) {
+        if let Some(mut record) = Self::source(src) {
+            record.status = SourceStatus::Quarantined;
+            record.failure = Some(kind);
+            Self::put(SOURCES, src.as_os_str().as_bytes(), Some(&record));
+        }
+    }
+
+    // Whether the source is quarantined and was not changed since
+    pub fn is_quarantined(src: &Path) -> bool {
+        let Some(record) = Self::source(src) else {
+            return false;
+        };
+        record.status == SourceStatus::Quarantined
+            && SourceIdentity::of(src, false)
+                .is_ok_and(|current| record.identity.matches(&current, src))
+    }
+
+    // Sources failed or quarantined on the last processing
+    pub fn failed_sources() -> Vec<(PathBuf, SourceRecord)> {
+        let res = (|| -> Result<_, redb::Error> {
+            let db = Self::db();
+            let txn = db.begin_read()?;
+            let table = txn.open_table(SOURCES)?;
+            Ok(table
+                .iter()?
+                .filter_map(|entry| {
+                    let (src, value) = entry.ok()?;
+                    let record: SourceRecord = serde_json::from_slice(value.value()).ok()?;
+                    (record.status != SourceStatus::Done)
+                        .then(|| (PathBuf::from(OsStr::from_bytes(src.value())), record))
+                })
+                .collect())
+        })();
+        res.unwrap_or_else(|err| {
+            warn!("Failed to read sources state: {err}");
+            vec![]
+        })
+    }
+
+    // Returns quarantined sources to failed ones. Returns cleared sources
+    pub fn clear_quarantine() -> Vec<PathBuf> {
+        Self::write(SOURCES.name(), |txn| {
+            let mut table = txn.open_table(SOURCES)?;
+            let quarantined = table
+                .iter()?
+                .filter_map(|entry| {
+                    let (src, value) = entry.ok()?;
+                    let record: SourceRecord = serde_json::from_slice(value.value()).ok()?;
+                    (record.status == SourceStatus::Quarantined)
+                        .then(|| (src.value().to_owned(), record))
+                })
+                .collect::<Vec<_>>();
+            for (src, mut record) in quarantined.iter().cloned() {
+                record.status = SourceStatus::Failed;
+                record.failure = None;
+                table.insert(src.as_slice(), Self::to_json(&record)?.as_slice())?;
+            }
+            Ok(quarantined
+                .into_iter()
+                .map(|(src, _)| PathBuf::from(OsStr::from_bytes(&src)))
+                .collect())
+        })
+        .unwrap_or_default()
+    }
+
+    pub(crate) fn probe(key: ProbeKey) -> Option<Probe> {
+        Self::get(PROBES, &key.to_bytes())
+    }
+
+    // Probe replaces the previous one of the same path, so changed files leave no stale probes
+    pub(crate) fn record_probe(path: &Path, key: ProbeKey, probe: &Probe) {
+        let previous = Self::put(PROBED, path.as_os_str().as_bytes(), Some(&key));
+        if let Some(previous) = previous
+            && previous != key
+        {
+            Self::put::<Probe>(PROBES, &previous.to_bytes(), None);
+        }
+        Self::put(PROBES, &key.to_bytes(), Some(probe));
+    }
+
+    pub(crate) fn push_job(job: &Job) -> Option<u64> {
+        Self::write(JOBS.name(), |txn| {
+            let mut table = txn.open_table(JOBS)?;
+            let queued = table
+                .iter()?
+                .filter_map(|entry| {
+                    let (id, value) = entry.ok()?;
+                    let stored: StoredJob = serde_json::from_slice(value.value()).ok()?;
+                    (!stored.running && stored.job.src == job.src && stored.job.root == job.root)
+                        .then_some((id.value(), stored))
+                })
+                .next();
+            if let Some((id, mut stored)) = queued {
+                // Queued recheck becomes the full processing, but not vice versa. Delayed retry
+                // starts anew as the source was changed
+                if !job.check_exists && (stored.job.check_exists || stored.job.attempt > 0) {
+                    stored.job = job.clone();
+                    table.insert(id, Self::to_json(&stored)?.as_slice())?;
+                }
+                return Ok(None);
+            }
+            let id = table.last()?.map_or(0, |(id, _)| id.value() + 1);
+            let stored = StoredJob {
+                job: job.clone(),
+                running: false,
+            };
+            table.insert(id, Self::to_json(&stored)?.as_slice())?;
+            Ok(Some(id))
+        })
+        .flatten()
+    }
+
+    // Takes the first queued job which is due and marks it as running
+    pub(crate) fn start_job() -> Option<(u64, Job)> {
+        let now = SystemTime::now();
+        Self::write(JOBS.name(), |txn| {
+            let mut table = txn.open_table(JOBS)?;
+            let queued = table
+                .iter()?
+                .filter_map(|entry| {
+                    let (id, value) = entry.ok()?;
+                    let stored: StoredJob = serde_json::from_slice(value.value()).ok()?;
+                    (!stored.running && stored.job.not_before.is_none_or(|time| time <= now))
+                        .then_some((id.value(), stored))
+                })
+                .next();
+            let Some((id, mut stored)) = queued else {
+                return Ok(None);
+            };
+            stored.running = true;
+            table.insert(id, Self::to_json(&stored)?.as_slice())?;
+            Ok(Some((id, stored.job)))
+        })
+        .flatten()
+    }
+
+    // Queued jobs in order with flag whether each is running
+    pub(crate) fn jobs() -> Vec<(u64, Job, bool)> {
+        let res = (|| -> Result<_, redb::Error> {
+            let db = Self::db();
+            let txn = db.begin_read()?;
+            let table = txn.open_table(JOBS)?;
+            Ok(table
+                .iter()?
+                .filter_map(|entry| {
+                    let (id, value) = entry.ok()?;
+                    let stored: StoredJob = serde_json::from_slice(value.value()).ok()?;
+                    Some((id.value(), stored.job, stored.running))
+                })
+                .collect())
+        })();
+        res.unwrap_or_else(|err| {
+            warn!("Failed to read jobs state: {err}");
+            vec![]
+        })
+    }
+
+    // Time when the first delayed job is due
+    pub(crate) fn earliest_retry() -> Option<SystemTime> {
+        let res = (|| -> Result<_, redb::Error> {
+            let db = Self::db();
+            let txn = db.begin_read()?;
+            let table = txn.open_table(JOBS)?;
+            Ok(table
+                .iter()?
+                .filter_map(|entry| {
+                    let (_, value) = entry.ok()?;
+                    let stored: StoredJob = serde_json::from_slice(value.value()).ok()?;
+                    stored.job.not_before.filter(|_| !stored.running)
+                })
+                .min())
+        })();
+        res.unwrap_or_else(|err| {
+            warn!("Failed to read jobs state: {err}");
+            None
+        })
+    }
+
+    // Returns the running job to queue with updated retry state
+    pub(crate) fn delay_job(id: u64, job: &Job) {
+        Self::write(JOBS.name(), |txn| {
+            let stored = StoredJob {
+                job: job.clone(),
+                running: false,
+            };
+            txn.open_table(JOBS)?
+                .insert(id, Self::to_json(&stored)?.as_slice())?;
+            Ok(())
+        });
+    }
+
+    pub(crate) fn finish_job(id: u64) {
+        Self::write(JOBS.name(), |txn| {
+            txn.open_table(JOBS)?.remove(id)?;
+            Ok(())
+        });
+    }
+
+    // Marks running jobs as queued. Returns number of queued jobs
+    pub(crate) fn requeue_jobs() -> usize {
+        Self::write(JOBS.name(), |txn| {
+            let mut table = txn.open_table(JOBS)?;
+            let jobs = table
+                .iter()?
+                .filter_map(|entry| {
+                    let (id, value) = entry.ok()?;
+                    Some((
+                        id.value(),
+                        serde_json::from_slice::<StoredJob>(value.value()).ok()?,
+                    ))
+                })
+                .collect::<Vec<_>>();
+            for (id, mut stored) in jobs.iter().cloned() {
+                if stored.running {
+                    stored.running = false;
+                    table.insert(id, Self::to_json(&stored)?.as_slice())?;
+                }
+            }
+            Ok(jobs.len())
+        })
+        .unwrap_or_default()
+    }
+
+    // Output which is being written and should be removed if not finished
+    pub(crate) fn record_partial(output: &Path) {
+        Self::put(PARTIALS, output.as_os_str().as_bytes(), Some(&()));
+    }
+
+    pub(crate) fn is_partial(output: &Path) -> bool {
+        Self::get::<()>(PARTIALS, output.as_os_str().as_bytes()).is_some()
+    }
+
+    pub(crate) fn forget_partial(output: &Path) {
+        Self::put::<()>(PARTIALS, output.as_os_str().as_bytes(), None);
+    }
+
+    pub(crate) fn take_partials() -> Vec<PathBuf> {
+        Self::write(PARTIALS.name(), |txn| {
+            let mut table = txn.open_table(PARTIALS)?;
+            let mut partials = vec![];
+            table.retain(|output, _| {
+                partials.push(PathBuf::from(OsStr::from_bytes(output)));
+                false
+            })?;
+            Ok(partials)
+        })
+        .unwrap_or_default()
+    }
+
+    fn write<R>(
+        name: &str,
+        f: impl FnOnce(&WriteTransaction) -> Result<R, redb::Error>,
+    ) -> Option<R> {
+        let res = (|| {
+            let db = Self::db();
+            let txn = db.begin_write()?;
+            let res = f(&txn)?;
+            txn.commit()?;
+            Ok::<_, redb::Error>(res)
+        })();
+        res.map_err(|err| warn!("Failed to save {name} state: {err}"))
+            .ok()
+    }
+
+    fn to_json<T: Serialize>(value: &T) -> Result<Vec<u8>, redb::Error> {
+        Ok(serde_json::to_vec(value).map_err(io::Error::from)?)
+    }
+
+    fn init(db: Database) -> Result<Database, redb::Error> {
+        let txn = db.begin_write()?;
+        txn.open_table(OUTPUTS)?;
+        txn.open_table(SOURCES)?;
+        txn.open_table(PROBES)?;
+        txn.open_table(PROBED)?;
+        txn.open_table(PARTIALS)?;
+        txn.open_table(JOBS)?;
+        txn.commit()?;
+        Ok(db)
+    }
+
+    fn db<'a>() -> RwLockReadGuard<'a, Database> {
+        DB.read().unwrap_or_else(|err| err.into_inner())
+    }
+
+    fn get<T: DeserializeOwned>(table: Table, key: &[u8]) -> Option<T> {
+        let res = (|| -> Result<_, redb::Error> {
+            let db = Self::db();
+            let txn = db.begin_read()?;
+            let table = txn.open_table(table)?;
+            let value = table.get(key)?;
+            Ok(value.and_then(|value| serde_json::from_slice(value.value()).ok()))
+        })();
+        res.unwrap_or_else(|err| {
+            warn!("Failed to read {} state: {err}", table.name());
+            None
+        })
+    }
+
+    // Replaces or removes the value. Returns the previous one
+    fn put<T: Serialize + DeserializeOwned>(
+        table: Table,
+        key: &[u8],
+        value: Option<&T>,
+    ) -> Option<T> {
+        let res = (|| -> Result<_, redb::Error> {
+            let db = Self::db();
+            let txn = db.begin_write()?;
+            let previous = {
+                let mut table = txn.open_table(table)?;
+                let previous = match value {
+                    Some(value) => {
+                        let value = serde_json::to_vec(value).map_err(io::Error::from)?;
+                        table.insert(key, value.as_slice())?
+                    }
+                    None => table.remove(key)?,
+                };
+                previous.and_then(|previous| serde_json::from_slice(previous.value()).ok())
+            };
+            txn.commit()?;
+            Ok(previous)
+        })();
+        res.unwrap_or_else(|err| {
+            warn!("Failed to save {} state: {err}", table.name());
+            None
+        })
+    }
+}
+
+impl OutputRecord {
+    // Whether the source was changed since the output was built
+    pub fn is_source_changed(&self) -> bool {
+        let Some(recorded) = &self.source else {
+            return false;
+        };
+        match SourceIdentity::of(&self.src, false) {
+            Ok(current) => !recorded.matches(&current, &self.src),
+            Err(_) => true,
+        }
+    }
+}
+
+impl SourceIdentity {
+    pub fn of(path: &Path, hash: bool) -> io::Result<Self> {
+        let stat = fs::metadata(path)?;
+        Ok(Self {
+            size: stat.len(),
+            mtime: stat.modified()?,
+            hash: if hash { Some(hash_file(path)?) } else { None },
+        })
+    }
+
+    // Source with the same size and mtime is considered the same. When mtime differs, but hash
+    // was recorded, the content is compared to not rebuild outputs of just touched or copied
+    // sources.
+    pub fn matches(&self, current: &SourceIdentity, path: &Path) -> bool {
+        if self.size != current.size {
+            return false;
+        }
+        if self.mtime == current.mtime {
+            return true;
+        }
+        match (&self.hash, &current.hash) {
+            (Some(recorded), Some(current)) => recorded == current,
+            (Some(recorded), None) => hash_file(path).is_ok_and(|current| *recorded == current),
+            _ => false,
+        }
+    }
+}
+
+impl TranscoderConfig {
+    // Fingerprint of everything which affects plans: policies, formats, codecs and the script
+    pub fn fingerprint(&self) -> String {
+        let config = TranscoderConfig {
+            dryrun: false,
+            ..self.clone()
+        };
+        // JSON is only a fallback to keep fingerprints of existing records. Debug output of
+        // configuration is not used, as it is TOML too
+        let mut data = match toml::to_string(&config) {
+            Ok(data) => data.into_bytes(),
+            Err(err) => {
+                warn!("Failed to serialize configuration for fingerprint: {err}");
+                serde_json::to_vec(&config).unwrap_or_else(|err| {
+                    warn!("Failed to serialize configuration for fingerprint as JSON: {err}");
+                    vec![]
+                })
+            }
+        };
+        if let Some(script) = &self.script {
+            data.extend(fs::read(script).unwrap_or_default());
+        }
+        fingerprint(&data)
+    }
+}
+
+// FNV-1a is used as it is stable between builds, unlike std hashers
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+
+fn fnv(hash: u64, data: &[u8]) -> u64 {
+    data.iter().fold(hash, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+pub(crate) fn fingerprint(data: &[u8]) -> String {
+    format!("{:016x}", fnv(FNV_OFFSET, data))
+}
+
+fn hash_file(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut buf = vec![0; 1 << 20];
+    let mut hash = FNV_OFFSET;
+    loop {
+        let len = file.read(&mut buf)?;
+        if len == 0 {
+            break;
+        }
+        hash = fnv(hash, &buf[..len]);
+    }
+    Ok(format!("{hash:016x}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(size: u64) -> ProbeKey {
+        ProbeKey {
+            dev: 1,
+            ino: 2,
+            size,
+            mtime: 3,
+            mtime_nsec: 4,
+        }
+    }
+
+    #[test]
+    fn probe_of_changed_file_replaces_previous() {
+        let path = Path::new("/media/changed.mkv");
+        let probe = Probe {
+            streams: Some(vec![]),
+        };
+        State::record_probe(path, key(10), &probe);
+        State::record_probe(path, key(10), &probe);
+        assert!(State::probe(key(10)).is_some());
+        State::record_probe(path, key(20), &probe);
+        assert!(State::probe(key(10)).is_none());
+        assert!(State::probe(key(20)).is_some());
+    }
+}
@@ -1,36 +1,52 @@
 use ez_ffmpeg::AVMediaType;
 use ez_ffmpeg::codec::{self as ffcodec, CodecInfo};
-use ez_ffmpeg::stream_info::{StreamInfo, find_all_stream_infos};
+use ez_ffmpeg::stream_info::StreamInfo;
 use ffmpeg_sys_next::AVCodecID;
-use log::{debug, info, trace};
+use log::{debug, info, trace, warn};
 use serde::ser::SerializeSeq;
 use serde::{Deserialize, Serialize};
 use std::collections::{BTreeSet, HashMap};
-use std::ffi::OsStr;
+use std::ffi::{OsStr, OsString};
 use std::fmt::Debug;
+use std::fmt::Write;
+use std::io::Read;
+use std::net::SocketAddr;
 use std::ops::Deref;
 use std::path::{Path, PathBuf};
-use std::process::Command;
-use std::sync::{LazyLock, Mutex, MutexGuard, RwLock, RwLockReadGuard};
+use std::process::{Child, Command, Output, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, LazyLock, Mutex, MutexGuard, RwLock, RwLockReadGuard};
+use std::time::Duration;
 use std::{fmt, io};
 
+use crate::config::{ConfigLayer, OutputConfig, WatchConfig};
+use crate::events::{self, Event};
+use crate::probe::{self, ProbeKey};
+use crate::progress::Progress;
+use crate::retry::{FfmpegError, RetryConfig};
+use crate::rules::{Policy, Rule};
+#[cfg(feature = "scripting")]
+use crate::script;
+use crate::state::{OutputRecord, SourceIdentity, SourceRecord, SourceStatus, State};
+
 pub struct Transcoder<'a> {
-    config: MutexGuard<'a, TranscoderConfig>,
+    // Only one job is performed at once
+    _job: MutexGuard<'a, ()>,
 }
 
-#[derive(Debug, Deserialize, Serialize, Hash)]
+#[derive(Debug, Clone, Deserialize, Serialize, Hash)]
 pub struct RequiredAudio {
     language: Option<String>,
 }
 
-#[derive(Debug, Deserialize, Serialize, Hash)]
+#[derive(Debug, Clone, Deserialize, Serialize, Hash)]
 pub struct RequiredSubtitle {
     language: Option<String>,
 }
 
-type FileExtension = String;
+pub(crate) type FileExtension = String;
 
-#[derive(Debug, PartialEq, Deserialize, Serialize, Hash, Eq, PartialOrd, Ord)]
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize, Hash, Eq, PartialOrd, Ord)]
 pub enum RequirementType {
     Video,
     Audio(RequiredAudio),
@@ -44,36 +60,65 @@ pub enum RequirementLevel {
     WithOther,
     Ignore,
     Decline,
+    // Streams are removed from output, so file with them is transcoded
+    Drop,
 }
 
 // Requirements are comparable to make them prioritized. One stream can be matched to several
 // requirements. E.g. requirement with language=Some(rus) and level=All and another requirement
 // with language=None and level=WithOther. In such case we should follow the most accurate
 // requirement
-#[derive(Debug, PartialEq, Deserialize, Serialize, Hash, Eq, PartialOrd, Ord)]
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize, Hash, Eq, PartialOrd, Ord)]
 pub struct Requirement {
-    what: RequirementType,
-    level: RequirementLevel,
+    pub(crate) what: RequirementType,
+    pub(crate) level: RequirementLevel,
 }
 
-#[derive(Default, Deserialize, Serialize)]
+// Configuration is deserialized through ConfigLayer to resolve referenced profile
+#[derive(Default, Clone, Deserialize, Serialize)]
+#[serde(try_from = "ConfigLayer")]
 pub struct TranscoderConfig {
-    #[serde(deserialize_with = "deserialize_formats", alias = "supported-formats")]
     pub supported_formats: Vec<FileExtension>,
-    #[serde(
-        deserialize_with = "deserialize_codecs",
-        serialize_with = "serialize_codecs",
-        alias = "supported-codecs"
-    )]
+    #[serde(serialize_with = "serialize_codecs")]
     pub supported_codecs: Vec<CodecInfoExtra>,
-    #[serde(alias = "requirements")]
     pub required: BTreeSet<Requirement>,
-    #[serde(default)]
+    pub rules: Vec<Rule>,
+    // Path to script which is able to alter the plan of each file
+    pub script: Option<PathBuf>,
     pub dryrun: bool,
+    // How to retry failed jobs
+    #[serde(skip_serializing)]
+    pub retry: RetryConfig,
+    // Whether to record hash of source content to detect replaced sources
+    #[serde(skip_serializing)]
+    pub hash_sources: bool,
+    // Where the state of outputs is stored
+    #[serde(skip_serializing)]
+    pub state: Option<PathBuf>,
+    // Where control requests are listened
+    #[serde(skip_serializing)]
+    pub socket: Option<PathBuf>,
+    // Address of HTTP endpoint with metrics, disabled if not set
+    #[serde(skip_serializing)]
+    pub metrics: Option<SocketAddr>,
+    // Where to write structured log of events, "-" for stdout
+    #[serde(skip_serializing)]
+    pub events: Option<PathBuf>,
+    // Overrides for particular destinations
+    #[serde(skip_serializing)]
+    pub outputs: Vec<OutputConfig>,
+    #[serde(skip_serializing)]
+    pub watch: Vec<WatchConfig>,
 }
 
-static CONFIG: LazyLock<Mutex<TranscoderConfig>> =
-    LazyLock::new(|| Mutex::new(TranscoderConfig::default()));
+static CONFIG: LazyLock<RwLock<Arc<TranscoderConfig>>> =
+    LazyLock::new(|| RwLock::new(Arc::new(TranscoderConfig::default())));
+
+static JOB: Mutex<()> = Mutex::new(());
+
+// Cancellation flags of running jobs by source path
+static RUNNING: LazyLock<Mutex<HashMap<PathBuf, Arc<AtomicBool>>>> =
+    LazyLock::new(Default::default);
 
 pub struct IndexedCodecs {
     encoders: HashMap<String, CodecInfoExtra>,
@@ -83,9 +128,8 @@ pub struct IndexedCodecs {
 #[derive(Clone, Debug)]
 pub struct CodecInfoExtra {
     codec: CodecInfo,
-    encoder: bool,
-    #[allow(dead_code)]
-    decoder: bool,
+    pub encoder: bool,
+    pub decoder: bool,
 }
 
 static CODECS: LazyLock<RwLock<IndexedCodecs>> =
@@ -93,25 +137,21 @@ static CODECS: LazyLock<RwLock<IndexedCodecs>> =
 
 #[derive(Debug)]
 enum MediaFile<'a> {
-    Input {
-        input: Streams,
-        config: &'a TranscoderConfig,
-        path: &'a Path,
-    },
-    Other {
-        path: &'a Path,
-    },
+    Input { input: Streams, path: &'a Path },
+    Other { path: &'a Path },
 }
 
 #[derive(Debug)]
 struct MediaFileTasks<'req> {
     config: &'req TranscoderConfig,
     tasks: Vec<RequirementTaks<'req>>,
+    #[cfg(feature = "scripting")]
+    scripted: Option<script::Plan>,
 }
 
 #[derive(Debug)]
 struct RequirementTaks<'req> {
-    requirement: &'req Requirement,
+    policy: Policy<'req>,
     tasks: Vec<TranscodeTask>,
 }
 
@@ -122,21 +162,60 @@ struct TranscodeTask {
 }
 
 #[derive(PartialEq, Clone)]
-enum TranscodeTaskType {
+pub(crate) enum TranscodeTaskType {
     Supported,
     Transcode(CodecInfoExtra),
+    Drop,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum DecidedBy<'req> {
+    Policy(Policy<'req>),
+    #[cfg(feature = "scripting")]
+    Script,
 }
 
 struct DebugTask {
     task: TranscodeTaskType,
     stream: StreamInfo,
+    // Description of the rule or requirement which decided the task
+    decided_by: Option<String>,
+}
+
+// Way to produce output from source planned for particular destination
+#[derive(Debug)]
+pub struct OutputPlan {
+    pub src: PathBuf,
+    // Destination with extension of output format
+    pub dst: PathBuf,
+    pub action: OutputAction,
+    // Decisions for streams of media source, empty for other files
+    pub streams: Vec<StreamPlan>,
+    // Why the whole file is transcoded or symlinked
+    pub reason: Option<String>,
+}
+
+// Decision made for stream of source
+#[derive(Debug, Clone, Serialize)]
+pub struct StreamPlan {
+    pub index: i32,
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub codec: Option<String>,
+    // Either copy, drop or name of codec to transcode into
+    pub action: String,
+    // Rule or requirement which decided the action
+    pub decided_by: Option<String>,
 }
 
-trait Transcodable {
-    fn transcode(self, dst: &Path, cfg: &TranscoderConfig) -> io::Result<()>;
+#[derive(Debug)]
+pub enum OutputAction {
+    Symlink,
+    // Arguments of ffmpeg describing output streams
+    Transcode(Vec<OsString>),
 }
 
-trait GetAVCodec {
+pub(crate) trait GetAVCodec {
     fn get_avcodec(&self) -> Option<AVCodecID>;
 }
 
@@ -144,7 +223,7 @@ trait GetAVMediaType {
     fn get_avmediatype(&self) -> AVMediaType;
 }
 
-trait GetIndex {
+pub(crate) trait GetIndex {
     fn get_index(&self) -> i32;
 }
 
@@ -159,119 +238,513 @@ macro_rules! drylog {
         }
     };
 }
+pub(crate) use drylog;
 
 impl<'a> MediaFile<'a> {
-    pub fn new(path: &'a Path, config: &'a TranscoderConfig) -> Self {
-        if let Ok(streams) = find_all_stream_infos(path.as_os_str().to_str().unwrap()) {
+    pub fn new(path: &'a Path) -> Self {
+        if let Some(streams) = probe::probe(path) {
             Self::Input {
                 input: streams,
-                config,
                 path,
             }
         } else {
             Self::Other { path }
         }
     }
+
+    pub fn plan(&self, dst: &Path, cfg: &TranscoderConfig) -> OutputPlan {
+        match self {
+            Self::Input { input, path } => Self::plan_streams(input, path, dst, cfg),
+            Self::Other { path } => OutputPlan {
+                reason: Some("not a media file".to_string()),
+                ..OutputPlan::symlink(path, dst)
+            },
+        }
+    }
+
+    fn plan_streams(
+        streams: &Streams,
+        src: &Path,
+        dst: &Path,
+        cfg: &TranscoderConfig,
+    ) -> OutputPlan {
+        let media = MediaFileTasks::new(streams, cfg, src);
+        // Collect streams to tasks list. Do not fold them at once to arguments
+        // to have a chance to debug them
+        let tasks: Vec<_> = streams
+            .iter()
+            .map(|stream| {
+                let (task, decided_by) = media.find_task_for(stream);
+                let decided_by = decided_by.map(|decided_by| decided_by.to_string());
+                DebugTask {
+                    stream: stream.clone(),
+                    task,
+                    decided_by,
+                }
+            })
+            .collect();
+        let stream_plans = tasks.iter().map(DebugTask::to_plan).collect();
+        let Some(reason) = media.transcode_reason(streams, src) else {
+            return OutputPlan {
+                streams: stream_plans,
+                ..OutputPlan::symlink(src, dst)
+            };
+        };
+        let mut dst = PathBuf::from(dst);
+        if let Some(format) = cfg.supported_formats.first() {
+            dst.set_extension(format);
+        }
+
+        drylog!(cfg, "Tasks for {src:?}->{dst:?}: {tasks:#?}");
+
+        let args = tasks
+            .into_iter()
+            .filter(|task| task.task != TranscodeTaskType::Drop)
+            .enumerate()
+            .flat_map(|(out, task)| {
+                // for each kept stream add its mapping and job. Output indexes differ from input
+                // ones when some streams are dropped
+                [
+                    OsString::from("-map"),
+                    format!("0:{}", task.stream.get_index()).into(),
+                    format!("-c:{out}").into(),
+                    task.task.as_ref().to_owned(),
+                ]
+            })
+            .collect();
+        OutputPlan {
+            src: src.to_owned(),
+            dst,
+            action: OutputAction::Transcode(args),
+            streams: stream_plans,
+            reason: Some(reason),
+        }
+    }
 }
 
 impl<'a> Transcoder<'a> {
+    // Plans of source for each destination without placing them
+    pub fn plan(src: &Path, dst: &[(PathBuf, TranscoderConfig)]) -> Vec<OutputPlan> {
+        let file = MediaFile::new(src);
+        dst.iter().map(|(dst, cfg)| file.plan(dst, cfg)).collect()
+    }
+
+    // Human readable account of planning the source: its streams with decisions for each of them,
+    // policies applied to them in order of priority, and decision for the whole file
+    pub fn explain(src: &Path, dst: &Path, cfg: &TranscoderConfig) -> String {
+        Self::explain_file(&MediaFile::new(src), dst, cfg)
+    }
+
+    fn explain_file(file: &MediaFile, dst: &Path, cfg: &TranscoderConfig) -> String {
+        let mut out = String::new();
+        let plan = file.plan(dst, cfg);
+        if let MediaFile::Input { input, path: src } = file {
+            let _ = writeln!(out, "Streams with decisions:");
+            for (stream, decision) in input.iter().zip(plan.streams.iter()) {
+                let _ = write!(out, "  {}: {}", decision.index, decision.kind);
+                if let Some(codec) = &decision.codec {
+                    let _ = write!(out, " {codec}");
+                }
+                let (StreamInfo::Video { metadata, .. }
+                | StreamInfo::Audio { metadata, .. }
+                | StreamInfo::Subtitle { metadata, .. }
+                | StreamInfo::Data { metadata, .. }
+                | StreamInfo::Attachment { metadata, .. }
+                | StreamInfo::Unknown { metadata, .. }) = stream;
+                if let Some(language) = metadata.get("language") {
+                    let _ = write!(out, " ({language})");
+                }
+                let decided_by = decision.decided_by.as_deref().unwrap_or("default");
+                let _ = writeln!(out, ": {} by {decided_by}", decision.action);
+            }
+            let media = MediaFileTasks::new(input, cfg, src);
+            let _ = writeln!(out, "Policies in order of priority:");
+            for req in media.tasks.iter() {
+                // Streams matched by policy, even ones it has no codec for
+                let matched: Vec<_> = input
+                    .iter()
+                    .filter(|stream| req.policy.applies(stream, input))
+                    .map(|stream| {
+                        let index = stream.get_index();
+                        match req.tasks.iter().find(|task| task.stream_index == index) {
+                            Some(task) => format!("{index}: {:?}", task.action),
+                            None => format!("{index}: no supported codec"),
+                        }
+                    })
+                    .collect();
+                let _ = writeln!(
+                    out,
+                    "  {} ({:?}): matched [{}], needs transcoding: {}",
+                    req.policy,
+                    req.get_level(),
+                    matched.join(", "),
+                    req.need_to_transcode()
+                );
+            }
+        }
+        let _ = write!(out, "Decision: {}", plan.kind());
+        if let Some(reason) = &plan.reason {
+            let _ = write!(out, " as {reason}");
+        }
+        let _ = writeln!(out, "\nOutput: {:?}", plan.dst);
+        let args = plan.ffmpeg_args();
+        if !args.is_empty() {
+            let args: Vec<_> = args.iter().map(|arg| arg.to_string_lossy()).collect();
+            let _ = writeln!(out, "Command: ffmpeg {}", args.join(" "));
+        }
+        out
+    }
+
     pub fn get() -> Self {
         Self {
-            config: TranscoderConfig::get(),
+            _job: JOB.lock().unwrap_or_else(|err| err.into_inner()),
         }
     }
-    pub fn transcode(self, src: &Path, dst: &Path) -> io::Result<()> {
-        MediaFile::new(src, &self.config).transcode(dst, &self.config)
+    // Source is probed once and then planned for each destination with its own configuration.
+    // With outdated_only destinations are rebuilt only when their recorded plan differs from the
+    // current one. Returns result for each destination.
+    pub fn transcode(
+        self,
+        src: &Path,
+        dst: &[(PathBuf, TranscoderConfig)],
+        outdated_only: bool,
+    ) -> Vec<io::Result<()>> {
+        let config = TranscoderConfig::get();
+        let cancelled = Arc::new(AtomicBool::new(false));
+        Self::running().insert(src.to_owned(), cancelled.clone());
+        let source = SourceIdentity::of(src, config.hash_sources).ok();
+        let file = MediaFile::new(src);
+        let results: Vec<_> = dst
+            .iter()
+            .map(|(dst, config)| {
+                Self::emplace(&file, dst, config, &source, outdated_only, &cancelled)
+            })
+            .collect();
+        Self::running().remove(src);
+        // Cancelled source is either gone or will be processed again
+        if !config.dryrun
+            && !cancelled.load(Ordering::Relaxed)
+            && let Some(identity) = source
+        {
+            let errors: Vec<_> = dst
+                .iter()
+                .zip(results.iter())
+                .filter_map(|((dst, _), res)| {
+                    res.as_ref().err().map(|err| format!("{dst:?}: {err}"))
+                })
+                .collect();
+            let status = if errors.is_empty() {
+                SourceStatus::Done
+            } else {
+                SourceStatus::Failed
+            };
+            State::record_source(
+                src,
+                SourceRecord {
+                    identity,
+                    status,
+                    errors,
+                    probe: ProbeKey::of(src),
+                    failure: None,
+                },
+            );
+        }
+        results
     }
-}
 
-impl Transcodable for MediaFile<'_> {
-    fn transcode(self, dst: &Path, cfg: &TranscoderConfig) -> io::Result<()> {
-        match self {
-            Self::Input {
-                input,
-                config,
-                path,
-            } => (input, config, path).transcode(dst, cfg),
-            Self::Other { path } => path.transcode(dst, cfg),
+    // Stops the running job of source, its outputs which are not placed yet are not placed.
+    // Returns whether the job was running
+    pub fn cancel(src: &Path) -> bool {
+        match Self::running().get(src) {
+            Some(cancelled) => {
+                cancelled.store(true, Ordering::Relaxed);
+                true
+            }
+            None => false,
         }
     }
-}
 
-impl Transcodable for &Path {
-    fn transcode(self, dst: &Path, cfg: &TranscoderConfig) -> io::Result<()> {
-        drylog!(cfg, "Placing symlink from {self:?} to {dst:?}");
+    // Error of outputs of cancelled jobs
+    pub fn is_cancelled(err: &io::Error) -> bool {
+        err.kind() == io::ErrorKind::Interrupted
+    }
+
+    fn running<'b>() -> MutexGuard<'b, HashMap<PathBuf, Arc<AtomicBool>>> {
+        RUNNING.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    fn emplace(
+        file: &MediaFile,
+        dst: &Path,
+        cfg: &TranscoderConfig,
+        source: &Option<SourceIdentity>,
+        outdated_only: bool,
+        cancelled: &AtomicBool,
+    ) -> io::Result<()> {
+        if cancelled.load(Ordering::Relaxed) {
+            return Err(cancelled_error());
+        }
+        let plan = file.plan(dst, cfg);
+        events::emit(Event::Planned {
+            src: &plan.src,
+            dst,
+            output: &plan.dst,
+            action: plan.kind(),
+            streams: &plan.streams,
+        });
+        let record = OutputRecord {
+            src: plan.src.clone(),
+            output: plan.dst.clone(),
+            config: cfg.fingerprint(),
+            plan: plan.fingerprint(),
+            source: source.clone(),
+        };
+        let recorded = State::output(dst);
+        if let Some(recorded) = &recorded {
+            let source_changed = recorded.is_source_changed();
+            if outdated_only
+                && !source_changed
+                && recorded.plan == record.plan
+                && recorded.output == record.output
+                && recorded.output.symlink_metadata().is_ok()
+            {
+                trace!("Plan of {dst:?} is unchanged");
+                if !cfg.dryrun {
+                    State::record(dst, record);
+                }
+                return Ok(());
+            }
+            if source_changed {
+                drylog!(
+                    cfg,
+                    "Rebuilding {:?} as {src:?} changed",
+                    recorded.output,
+                    src = plan.src
+                );
+            } else {
+                drylog!(
+                    cfg,
+                    "Rebuilding {:?} planned as {:?}, now as {:?}",
+                    recorded.output,
+                    recorded.plan,
+                    record.plan
+                );
+            }
+            if !cfg.dryrun {
+                remove_output(&recorded.output)?;
+                events::emit(Event::Removed {
+                    output: &recorded.output,
+                    reason: "rebuild",
+                });
+            }
+        }
+        plan.execute(cfg, cancelled)?;
+        // Source may be removed right before output is placed, so do not let it outlive source
+        if cancelled.load(Ordering::Relaxed) {
+            if !cfg.dryrun {
+                remove_output(&plan.dst)?;
+            }
+            return Err(cancelled_error());
+        }
         if !cfg.dryrun {
-            std::fs::create_dir_all(dst.parent().unwrap_or(Path::new("/")))?;
-            std::os::unix::fs::symlink(self, dst)?;
+            events::emit(Event::Published {
+                src: &plan.src,
+                output: &plan.dst,
+            });
+            State::record(dst, record);
         }
         Ok(())
     }
 }
 
-impl Transcodable for (Streams, &TranscoderConfig, &Path) {
-    fn transcode(self, dst: &Path, cfg: &TranscoderConfig) -> io::Result<()> {
-        let (streams, config, src) = self;
-        let tasks = MediaFileTasks::new(&streams, config);
-        if tasks.need_to_transcode(src) {
-            (streams, tasks, src).transcode(dst, cfg)
-        } else {
-            src.transcode(dst, cfg)
+impl OutputPlan {
+    fn symlink(src: &Path, dst: &Path) -> Self {
+        Self {
+            src: src.to_owned(),
+            dst: dst.to_owned(),
+            action: OutputAction::Symlink,
+            streams: vec![],
+            reason: None,
+        }
+    }
+
+    // Short name of action: symlink, remux when all kept streams are copied or transcode
+    pub fn kind(&self) -> &'static str {
+        match self.action {
+            OutputAction::Symlink => "symlink",
+            OutputAction::Transcode(_)
+                if self
+                    .streams
+                    .iter()
+                    .all(|stream| stream.action == "copy" || stream.action == "drop") =>
+            {
+                "remux"
+            }
+            OutputAction::Transcode(_) => "transcode",
+        }
+    }
+
+    // Stable description of plan to detect outputs built with another one
+    pub fn fingerprint(&self) -> String {
+        match &self.action {
+            OutputAction::Symlink => "symlink".to_string(),
+            OutputAction::Transcode(args) => args
+                .iter()
+                .map(|arg| arg.to_string_lossy())
+                .collect::<Vec<_>>()
+                .join(" "),
         }
     }
+
+    // Hidden file near the output with the same extension to keep format detection of ffmpeg
+    fn partial(&self) -> PathBuf {
+        let mut name = OsString::from(".");
+        name.push(self.dst.file_stem().unwrap_or_default());
+        name.push(".partial");
+        if let Some(ext) = self.dst.extension() {
+            name.push(".");
+            name.push(ext);
+        }
+        self.dst.with_file_name(name)
+    }
+
+    // Arguments ffmpeg is called with except log level, which depends on the run. Empty for symlinks
+    pub fn ffmpeg_args(&self) -> Vec<OsString> {
+        let OutputAction::Transcode(args) = &self.action else {
+            return vec![];
+        };
+        let mut cmd = vec![OsString::from("-y")]; // Agree with all;
+        cmd.extend(["-progress", "pipe:1", "-nostats"].map(OsString::from)); // Progress is parsed from stdout
+        cmd.push("-i".into());
+        cmd.push(self.src.clone().into()); // add input;
+        cmd.extend(args.iter().cloned());
+        // Output is written aside and moved into place when done, so interrupted
+        // transcoding never leaves broken output
+        cmd.push(self.partial().into()); // Finally - set the output
+        cmd
+    }
+
+    fn execute(&self, cfg: &TranscoderConfig, cancelled: &AtomicBool) -> io::Result<()> {
+        let (src, dst) = (&self.src, &self.dst);
+        match &self.action {
+            OutputAction::Symlink => {
+                drylog!(cfg, "Placing symlink from {src:?} to {dst:?}");
+                if !cfg.dryrun {
+                    std::fs::create_dir_all(dst.parent().unwrap_or(Path::new("/")))?;
+                    std::os::unix::fs::symlink(src, dst)?;
+                }
+            }
+            OutputAction::Transcode(_) => {
+                if !cfg.dryrun {
+                    std::fs::create_dir_all(dst.parent().unwrap_or(Path::new("/")))?;
+                    let mut cmd = Command::new("ffmpeg");
+                    if log::max_level() <= log::LevelFilter::Info {
+                        cmd.arg("-loglevel").arg("error"); // In common we do not need to see ffmpeg logs
+                    }
+                    cmd.args(self.ffmpeg_args());
+                    let partial = self.partial();
+                    info!("Transcoding {src:?} to {dst:?}");
+                    trace!("Calling ffmpeg: {cmd:#?}");
+                    // Output of ffmpeg is kept to classify its failures
+                    cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
+                    State::record_partial(&partial);
+                    #[cfg(feature = "metrics")]
+                    let started = std::time::Instant::now();
+                    let res = cmd.spawn().and_then(|child| self.wait(child, cancelled));
+                    let res = match res {
+                        Ok(output) => {
+                            // Last lines are enough to see the reason
+                            let stderr = &output.stderr[output.stderr.len().saturating_sub(4096)..];
+                            let stderr = String::from_utf8_lossy(stderr).into_owned();
+                            if !stderr.is_empty() {
+                                debug!("ffmpeg output: {stderr}");
+                            }
+                            if output.status.success() {
+                                std::fs::rename(&partial, dst)
+                            } else {
+                                Err(io::Error::other(FfmpegError {
+                                    status: output.status,
+                                    stderr,
+                                }))
+                            }
+                        }
+                        Err(err) => Err(err),
+                    };
+                    if res.is_err() {
+                        remove_output(&partial)?;
+                    }
+                    State::forget_partial(&partial);
+                    res?;
+                    #[cfg(feature = "metrics")]
+                    crate::metrics::transcoded(src, dst, started.elapsed(), probe::duration(src));
+                    info!("Transcoding to {dst:?} done");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Waits for ffmpeg and kills it when cancelled. Output is read aside, so ffmpeg is not
+    // blocked on the full pipe
+    fn wait(&self, mut child: Child, cancelled: &AtomicBool) -> io::Result<Output> {
+        if let Some(stdout) = child.stdout.take() {
+            let (src, dst) = (self.src.clone(), self.dst.clone());
+            std::thread::spawn(move || Progress::track(&src, &dst, stdout));
+        }
+        let stderr = child.stderr.take();
+        let reader = std::thread::spawn(move || {
+            let mut buf = vec![];
+            if let Some(mut stderr) = stderr {
+                let _ = stderr.read_to_end(&mut buf);
+            }
+            buf
+        });
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+            if cancelled.load(Ordering::Relaxed) {
+                debug!("Killing ffmpeg {}", child.id());
+                child.kill()?;
+                child.wait()?;
+                return Err(cancelled_error());
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        };
+        Ok(Output {
+            status,
+            stdout: vec![],
+            stderr: reader.join().unwrap_or_default(),
+        })
+    }
 }
 
 impl TranscoderConfig {
-    pub fn get<'a>() -> MutexGuard<'a, TranscoderConfig> {
-        CONFIG.lock().unwrap()
+    pub fn get() -> Arc<TranscoderConfig> {
+        CONFIG.read().unwrap().clone()
     }
     pub fn set(config: TranscoderConfig) {
-        let mut s = Self::get();
-        *s = config;
+        let mut s = CONFIG.write().unwrap();
+        *s = Arc::new(config);
     }
 }
 
-impl Transcodable for (Streams, MediaFileTasks<'_>, &Path) {
-    fn transcode(self, dst: &Path, cfg: &TranscoderConfig) -> io::Result<()> {
-        std::fs::create_dir_all(dst.parent().unwrap_or(Path::new("/")))?;
-        let (streams, tasks, src) = self;
-        let mut dst = PathBuf::from(dst);
-        dst.set_extension(&cfg.supported_formats[0]);
-
-        // Collect streams to tasks list. Do not fold them at once to arguments
-        // to have a chance to debug them
-        let tasks: Vec<_> = streams
-            .into_iter()
-            .map(|stream| {
-                let task = tasks.find_task_for(&stream);
-                DebugTask { stream, task }
-            })
-            .collect();
-
-        drylog!(cfg, "Tasks for {src:?}->{dst:?}: {tasks:#?}");
+// Error of outputs whose job was cancelled
+fn cancelled_error() -> io::Error {
+    io::Error::new(io::ErrorKind::Interrupted, "Transcoding cancelled")
+}
 
-        if !cfg.dryrun {
-            let mut cmd = Command::new("ffmpeg");
-            cmd.arg("-y"); // Agree with all;
-            if log::max_level() <= log::LevelFilter::Info {
-                cmd.arg("-loglevel").arg("error"); // In common we do not need to see ffmpeg logs
-            }
-            cmd.arg("-i").arg(src); // add input;
-            cmd.arg("-map").arg("0"); // start mapping for single input;
+// Whether output is named as the one being written, see `OutputPlan::partial`
+pub(crate) fn is_partial(output: &Path) -> bool {
+    let name = output.file_name().unwrap_or_default().to_string_lossy();
+    name.starts_with('.') && (name.contains(".partial.") || name.ends_with(".partial"))
+}
 
-            tasks.into_iter().fold(&mut cmd, |cmd, task| {
-                // for each stream add its mapping job to command
-                cmd.arg(&format!("-c:{}", task.stream.get_index()))
-                    .arg(&task.task)
-            });
-            cmd.arg(&dst); // Finally - set the output
-            info!("Transcoding {src:?} to {dst:?}");
-            trace!("Calling ffmpeg: {cmd:#?}");
-            let mut child = cmd.spawn()?;
-            child.wait()?;
-            info!("Transcoding to {dst:?} done");
-        }
-        Ok(())
+// Removes previously built output, both file and symlink
+pub(crate) fn remove_output(output: &Path) -> io::Result<()> {
+    match std::fs::remove_file(output) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
     }
 }
 
@@ -329,6 +802,18 @@ impl IndexedCodecs {
         self.encoders.get(&lower)
     }
 
+    // Each codec once, whether it is indexed as encoder or decoder, sorted by name
+    pub fn codecs(&self) -> Vec<&CodecInfoExtra> {
+        let mut codecs: Vec<_> = self
+            .decoders
+            .values()
+            .chain(self.encoders.values())
+            .collect();
+        codecs.sort_by(|a, b| a.codec_name.cmp(&b.codec_name));
+        codecs.dedup_by(|a, b| a.codec_name == b.codec_name);
+        codecs
+    }
+
     pub fn find(name: &str) -> Option<CodecInfoExtra> {
         Self::get().find_in(name).cloned()
     }
@@ -359,16 +844,72 @@ impl IndexedCodecs {
 }
 
 impl<'req> MediaFileTasks<'req> {
-    pub fn new(streams: &Streams, config: &'req TranscoderConfig) -> Self {
+    pub fn new(streams: &Streams, config: &'req TranscoderConfig, src: &Path) -> Self {
         let mut tasks = vec![];
 
-        for req in config.required.iter() {
-            tasks.push(RequirementTaks::<'req>::new(config, streams, req));
+        for policy in config.policies() {
+            tasks.push(RequirementTaks::<'req>::new(config, streams, policy));
+        }
+        #[cfg_attr(not(feature = "scripting"), allow(unused_mut))]
+        let mut tasks = Self {
+            config,
+            tasks,
+            #[cfg(feature = "scripting")]
+            scripted: None,
+        };
+        if let Some(script) = &config.script {
+            tasks.run_script(script, streams, src);
+        }
+        tasks
+    }
+
+    #[cfg(feature = "scripting")]
+    fn run_script(&mut self, script: &Path, streams: &Streams, src: &Path) {
+        let default = script::Plan {
+            transcode: self.policy_reason(streams, src).is_some(),
+            streams: streams
+                .iter()
+                .map(|stream| (stream.get_index(), self.policy_task_for(stream).0))
+                .collect(),
+        };
+        match script::run(script, src, streams, default) {
+            Ok(plan) => {
+                trace!("Script {script:?} planned {src:?}: {plan:?}");
+                self.scripted = Some(plan);
+            }
+            Err(err) => warn!("Script {script:?} failed on {src:?}, using default plan: {err}"),
+        }
+    }
+
+    #[cfg(not(feature = "scripting"))]
+    fn run_script(&self, script: &Path, _streams: &Streams, _src: &Path) {
+        warn!("Built without scripting support, ignoring {script:?}");
+    }
+
+    // Why the file has to be transcoded or None if it may be used as is
+    fn transcode_reason(&self, streams: &Streams, src: &Path) -> Option<String> {
+        let reason = self.policy_reason(streams, src);
+        #[cfg(feature = "scripting")]
+        if let Some(plan) = &self.scripted {
+            if plan.transcode {
+                return reason.or_else(|| Some("decided by script".to_string()));
+            }
+            // Streams dropped or transcoded by script can not be placed without transcoding
+            return streams
+                .iter()
+                .find_map(|stream| match self.find_task_for(stream) {
+                    (TranscodeTaskType::Supported, _) => None,
+                    (_, Some(DecidedBy::Script)) => Some(format!(
+                        "stream {} is changed by script",
+                        stream.get_index()
+                    )),
+                    _ => None,
+                });
         }
-        Self { config, tasks }
+        reason
     }
 
-    pub fn need_to_transcode(&self, src: &Path) -> bool {
+    fn policy_reason(&self, streams: &Streams, src: &Path) -> Option<String> {
         if let Some(format) = get_format(src) {
             let mut format_supported = false;
             for supp in self.config.supported_formats.iter() {
@@ -377,22 +918,52 @@ impl<'req> MediaFileTasks<'req> {
                 }
             }
             if !format_supported {
-                return true;
+                return Some(format!("format {format} is not supported"));
             }
         }
         for task in self.tasks.iter() {
             if task.need_to_transcode() {
-                return true;
+                return Some(format!("required by {}", task.policy));
             }
         }
-        false
+        for stream in streams.iter() {
+            if let (TranscodeTaskType::Drop, Some(decided_by)) = self.policy_task_for(stream) {
+                return Some(format!(
+                    "stream {} is dropped by {decided_by}",
+                    stream.get_index()
+                ));
+            }
+        }
+        None
+    }
+
+    // Script decides only streams it changed, others are attributed to policies
+    fn find_task_for<'a>(
+        &'a self,
+        stream: &StreamInfo,
+    ) -> (TranscodeTaskType, Option<DecidedBy<'req>>) {
+        let (task, decided_by) = self.policy_task_for(stream);
+        #[cfg(feature = "scripting")]
+        if let Some(scripted) = self
+            .scripted
+            .as_ref()
+            .and_then(|plan| plan.streams.get(&stream.get_index()))
+            && *scripted != task
+        {
+            return (scripted.clone(), Some(DecidedBy::Script));
+        }
+        (task, decided_by)
     }
-    fn find_task_for<'a>(&'a self, stream: &StreamInfo) -> TranscodeTaskType {
+
+    fn policy_task_for<'a>(
+        &'a self,
+        stream: &StreamInfo,
+    ) -> (TranscodeTaskType, Option<DecidedBy<'req>>) {
         let mut final_task = None;
-        for task in self.tasks.iter() {
-            for task in task.tasks.iter() {
+        for req in self.tasks.iter() {
+            for task in req.tasks.iter() {
                 if task.stream_index == stream.get_index() {
-                    final_task = Some(task);
+                    final_task = Some((task, req.policy));
                     break;
                 }
             }
@@ -401,36 +972,36 @@ impl<'req> MediaFileTasks<'req> {
             }
         }
         final_task
-            .map(|task| task.action.clone())
-            .unwrap_or(TranscodeTaskType::Supported)
+            .map(|(task, policy)| (task.action.clone(), Some(DecidedBy::Policy(policy))))
+            .unwrap_or((TranscodeTaskType::Supported, None))
     }
 }
 
 impl<'req> RequirementTaks<'req> {
-    pub fn new(
-        config: &'req TranscoderConfig,
-        streams: &Streams,
-        requirement: &'req Requirement,
-    ) -> Self {
+    pub fn new(config: &'req TranscoderConfig, streams: &Streams, policy: Policy<'req>) -> Self {
         let mut tasks = Vec::<TranscodeTask>::default();
         for stream in streams.iter() {
-            if *requirement == *stream {
-                if let Some(task) = TranscodeTask::new(stream, config) {
+            if policy.applies(stream, streams) {
+                if policy.get_level() == RequirementLevel::Drop {
+                    tasks.push(TranscodeTask::drop(stream));
+                } else if let Some(task) = TranscodeTask::new(stream, config) {
                     tasks.push(task);
                 }
             }
         }
-        Self { requirement, tasks }
+        Self { policy, tasks }
     }
     pub fn get_level(&self) -> RequirementLevel {
-        self.requirement.level
+        self.policy.get_level()
     }
     pub fn need_to_transcode(&self) -> bool {
         let level = self.get_level();
+        // Dropped streams are checked by file, as another policy may have decided them first
         if self.tasks.is_empty()
             || level == RequirementLevel::WithOther
             || level == RequirementLevel::Ignore
             || level == RequirementLevel::Decline
+            || level == RequirementLevel::Drop
         {
             return false;
         }
@@ -455,6 +1026,28 @@ impl PartialEq<StreamInfo> for Requirement {
     }
 }
 
+impl RequirementType {
+    pub(crate) fn media_type(&self) -> AVMediaType {
+        match self {
+            Self::Video => AVMediaType::AVMEDIA_TYPE_VIDEO,
+            Self::Audio(_) => AVMediaType::AVMEDIA_TYPE_AUDIO,
+            Self::Subtitle(_) => AVMediaType::AVMEDIA_TYPE_SUBTITLE,
+        }
+    }
+
+    // Whether some stream may match both types
+    pub(crate) fn overlaps(&self, other: &RequirementType) -> bool {
+        let same =
+            |lh: &Option<String>, rh: &Option<String>| lh.is_none() || rh.is_none() || lh == rh;
+        match (self, other) {
+            (Self::Video, Self::Video) => true,
+            (Self::Audio(lh), Self::Audio(rh)) => same(&lh.language, &rh.language),
+            (Self::Subtitle(lh), Self::Subtitle(rh)) => same(&lh.language, &rh.language),
+            _ => false,
+        }
+    }
+}
+
 impl PartialEq<StreamInfo> for RequirementType {
     fn eq(&self, stream: &StreamInfo) -> bool {
         match self {
@@ -503,6 +1096,12 @@ impl<'file> TranscodeTask {
             action,
         })
     }
+    pub fn drop(stream: &StreamInfo) -> Self {
+        Self {
+            stream_index: stream.get_index(),
+            action: TranscodeTaskType::Drop,
+        }
+    }
     pub fn need_to_transcode(&self) -> bool {
         return self.action != TranscodeTaskType::Supported;
     }
@@ -513,6 +1112,17 @@ impl fmt::Debug for TranscodeTaskType {
         match self {
             Self::Supported => write!(f, "Supported"),
             Self::Transcode(codec) => write!(f, "Transcode to {}", codec.codec_long_name),
+            Self::Drop => write!(f, "Drop"),
+        }
+    }
+}
+
+impl fmt::Display for DecidedBy<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Policy(policy) => write!(f, "{policy}"),
+            #[cfg(feature = "scripting")]
+            Self::Script => write!(f, "script"),
         }
     }
 }
@@ -522,6 +1132,8 @@ impl AsRef<OsStr> for TranscodeTaskType {
         match self {
             TranscodeTaskType::Supported => OsStr::new("copy"),
             TranscodeTaskType::Transcode(codec) => OsStr::new(&codec.desc_name),
+            // Dropped streams are not mapped to output, so should never be passed to ffmpeg
+            TranscodeTaskType::Drop => OsStr::new(""),
         }
     }
 }
@@ -594,7 +1206,7 @@ fn prioritize<T: Ord>(lh: &Option<T>, rh: &Option<T>) -> std::cmp::Ordering {
     }
 }
 
-fn deserialize_codecs<'de, D>(deserializer: D) -> Result<Vec<CodecInfoExtra>, D::Error>
+pub(crate) fn deserialize_codecs<'de, D>(deserializer: D) -> Result<Vec<CodecInfoExtra>, D::Error>
 where
     D: serde::Deserializer<'de>,
 {
@@ -609,6 +1221,31 @@ where
     Ok(res)
 }
 
+pub(crate) fn deserialize_codec<'de, D>(deserializer: D) -> Result<Option<CodecInfoExtra>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let Some(id_str) = Option::<String>::deserialize(deserializer)? else {
+        return Ok(None);
+    };
+    let codec = IndexedCodecs::find(&id_str)
+        .ok_or_else(|| serde::de::Error::custom(&format!("Unknown codec {id_str}")))?;
+    Ok(Some(codec))
+}
+
+pub(crate) fn serialize_codec<S>(
+    codec: &Option<CodecInfoExtra>,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    match codec {
+        Some(c) => serializer.serialize_some(&c.codec_long_name),
+        None => serializer.serialize_none(),
+    }
+}
+
 fn serialize_codecs<S>(codecs: &Vec<CodecInfoExtra>, serializer: S) -> Result<S::Ok, S::Error>
 where
     S: serde::Serializer,
@@ -620,7 +1257,7 @@ where
     sec.end()
 }
 
-fn deserialize_formats<'de, D>(deserializer: D) -> Result<Vec<FileExtension>, D::Error>
+pub(crate) fn deserialize_formats<'de, D>(deserializer: D) -> Result<Vec<FileExtension>, D::Error>
 where
     D: serde::Deserializer<'de>,
 {
@@ -702,6 +1339,29 @@ impl GetIndex for StreamInfo {
     }
 }
 
+impl DebugTask {
+    fn to_plan(&self) -> StreamPlan {
+        let codec = match &self.stream {
+            StreamInfo::Video { codec_name, .. }
+            | StreamInfo::Audio { codec_name, .. }
+            | StreamInfo::Subtitle { codec_name, .. }
+            | StreamInfo::Attachment { codec_name, .. } => Some(codec_name.clone()),
+            StreamInfo::Data { .. } | StreamInfo::Unknown { .. } => None,
+        };
+        let action = match &self.task {
+            TranscodeTaskType::Drop => "drop".to_string(),
+            task => task.as_ref().to_string_lossy().into_owned(),
+        };
+        StreamPlan {
+            index: self.stream.get_index(),
+            kind: self.stream.stream_type(),
+            codec,
+            action,
+            decided_by: self.decided_by.clone(),
+        }
+    }
+}
+
 impl Debug for DebugTask {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
@@ -711,6 +1371,266 @@ impl Debug for DebugTask {
             self.stream.get_avcodec(),
             self.stream.stream_type(),
             self.task
+        )?;
+        if let Some(decided_by) = &self.decided_by {
+            write!(f, " by {decided_by}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use ffmpeg_sys_next::{AVChannelOrder, AVRational};
+
+    const TIME_BASE: AVRational = AVRational { num: 1, den: 1000 };
+
+    fn metadata(language: Option<&str>) -> HashMap<String, String> {
+        language
+            .map(|language| ("language".to_string(), language.to_string()))
+            .into_iter()
+            .collect()
+    }
+
+    // Name of codec as ffmpeg reports it
+    fn codec_name(codec_id: AVCodecID) -> String {
+        format!("{codec_id:?}")
+            .trim_start_matches("AV_CODEC_ID_")
+            .to_lowercase()
+    }
+
+    pub(crate) fn video(index: i32, codec_id: AVCodecID) -> StreamInfo {
+        StreamInfo::Video {
+            index,
+            time_base: TIME_BASE,
+            start_time: 0,
+            duration: 0,
+            nb_frames: 0,
+            r_frame_rate: TIME_BASE,
+            sample_aspect_ratio: AVRational { num: 1, den: 1 },
+            metadata: HashMap::new(),
+            avg_frame_rate: TIME_BASE,
+            codec_id,
+            codec_name: codec_name(codec_id),
+            width: 1920,
+            height: 1080,
+            bit_rate: 0,
+            pixel_format: 0,
+            video_delay: 0,
+            fps: 25.0,
+            rotate: 0,
+        }
+    }
+
+    pub(crate) fn audio(index: i32, codec_id: AVCodecID, language: Option<&str>) -> StreamInfo {
+        StreamInfo::Audio {
+            index,
+            time_base: TIME_BASE,
+            start_time: 0,
+            duration: 0,
+            nb_frames: 0,
+            metadata: metadata(language),
+            avg_frame_rate: TIME_BASE,
+            codec_id,
+            codec_name: codec_name(codec_id),
+            sample_rate: 48000,
+            order: AVChannelOrder::AV_CHANNEL_ORDER_NATIVE,
+            nb_channels: 2,
+            bit_rate: 0,
+            sample_format: 0,
+            frame_size: 0,
+        }
+    }
+
+    pub(crate) fn subtitle(index: i32, codec_id: AVCodecID, language: Option<&str>) -> StreamInfo {
+        StreamInfo::Subtitle {
+            index,
+            time_base: TIME_BASE,
+            start_time: 0,
+            duration: 0,
+            nb_frames: 0,
+            metadata: metadata(language),
+            codec_id,
+            codec_name: codec_name(codec_id),
+        }
+    }
+
+    fn plan(config: &str, streams: &Streams) -> OutputPlan {
+        let config: TranscoderConfig = toml::from_str(config).unwrap();
+        MediaFile::plan_streams(
+            streams,
+            Path::new("movie.mkv"),
+            Path::new("movie.mkv"),
+            &config,
         )
     }
+
+    fn args(plan: &OutputPlan) -> Vec<&str> {
+        match &plan.action {
+            OutputAction::Transcode(args) => args.iter().map(|arg| arg.to_str().unwrap()).collect(),
+            OutputAction::Symlink => vec![],
+        }
+    }
+
+    const CONFIG: &str = r#"
+        supported-formats = ["mkv"]
+        supported-codecs = ["libx264", "aac", "subrip"]
+        requirements = [
+            { what = "Video", level = "All" },
+            { what = { Audio = {} }, level = "All" },
+            { what = { Subtitle = {} }, level = "WithOther" },
+        ]
+    "#;
+
+    #[test]
+    fn dropped_streams_are_not_mapped() {
+        let config = format!(
+            "{CONFIG}\n{}",
+            r#"
+            [[rules]]
+            name = "no-rus"
+            priority = 1
+            what = { Audio = { language = "rus" } }
+            level = "Drop"
+            "#
+        );
+        let plan = plan(
+            &config,
+            &vec![
+                video(0, AVCodecID::AV_CODEC_ID_H264),
+                audio(1, AVCodecID::AV_CODEC_ID_AAC, Some("rus")),
+                audio(2, AVCodecID::AV_CODEC_ID_AAC, Some("eng")),
+                subtitle(3, AVCodecID::AV_CODEC_ID_SUBRIP, Some("eng")),
+            ],
+        );
+        assert_eq!(
+            plan.reason.as_deref(),
+            Some("stream 1 is dropped by rule \"no-rus\"")
+        );
+        // Output indexes of streams after the dropped one are shifted
+        assert_eq!(
+            args(&plan),
+            [
+                "-map", "0:0", "-c:0", "copy", "-map", "0:2", "-c:1", "copy", "-map", "0:3",
+                "-c:2", "copy"
+            ]
+        );
+        assert_eq!(plan.streams[1].action, "drop");
+        assert_eq!(
+            plan.streams[1].decided_by.as_deref(),
+            Some("rule \"no-rus\"")
+        );
+    }
+
+    #[test]
+    fn drop_of_missing_streams_keeps_file() {
+        let config = format!(
+            "{CONFIG}\n{}",
+            r#"
+            [[rules]]
+            priority = 1
+            what = { Audio = { language = "rus" } }
+            level = "Drop"
+            "#
+        );
+        let plan = plan(
+            &config,
+            &vec![
+                video(0, AVCodecID::AV_CODEC_ID_H264),
+                audio(1, AVCodecID::AV_CODEC_ID_AAC, Some("eng")),
+            ],
+        );
+        assert_eq!(plan.reason, None);
+        assert!(matches!(plan.action, OutputAction::Symlink));
+    }
+
+    #[test]
+    fn declined_streams_are_kept() {
+        let config = r#"
+            supported-formats = ["mkv"]
+            supported-codecs = ["libx264", "aac"]
+            requirements = [
+                { what = "Video", level = "All" },
+                { what = { Audio = {} }, level = "Decline" },
+            ]
+        "#;
+        let streams = vec![
+            video(0, AVCodecID::AV_CODEC_ID_H264),
+            audio(1, AVCodecID::AV_CODEC_ID_AC3, None),
+        ];
+        let declined = plan(config, &streams);
+        assert_eq!(declined.reason, None);
+        assert!(matches!(declined.action, OutputAction::Symlink));
+        // Unlike declined streams, dropped ones make file transcoded
+        let dropped = plan(&config.replace("Decline", "Drop"), &streams);
+        assert_eq!(
+            dropped.reason.as_deref(),
+            Some("stream 1 is dropped by requirement Audio(RequiredAudio { language: None })/Drop")
+        );
+        assert_eq!(args(&dropped), ["-map", "0:0", "-c:0", "copy"]);
+    }
+
+    #[test]
+    fn explanation_lists_all_streams() {
+        // There is no codec for subtitles, so policy for them decides nothing
+        let config: TranscoderConfig = toml::from_str(
+            r#"
+            supported-formats = ["mkv"]
+            supported-codecs = ["libx264", "aac"]
+            requirements = [
+                { what = "Video", level = "All" },
+                { what = { Audio = {} }, level = "All" },
+                { what = { Subtitle = {} }, level = "WithOther" },
+            ]
+            "#,
+        )
+        .unwrap();
+        let file = MediaFile::Input {
+            input: vec![
+                video(0, AVCodecID::AV_CODEC_ID_HEVC),
+                audio(1, AVCodecID::AV_CODEC_ID_AAC, Some("eng")),
+                audio(2, AVCodecID::AV_CODEC_ID_TRUEHD, Some("rus")),
+                subtitle(3, AVCodecID::AV_CODEC_ID_SUBRIP, None),
+            ],
+            path: Path::new("movie.mkv"),
+        };
+        let out = Transcoder::explain_file(&file, Path::new("movie.mkv"), &config);
+        let audio = "requirement Audio(RequiredAudio { language: None })/All";
+        let subtitle = "requirement Subtitle(RequiredSubtitle { language: None })/WithOther";
+        for line in [
+            "  0: Video hevc: h264 by requirement Video/All".to_string(),
+            format!("  1: Audio aac (eng): copy by {audio}"),
+            format!("  2: Audio truehd (rus): aac by {audio}"),
+            "  3: Subtitle subrip: copy by default".to_string(),
+            "  requirement Video/All (All): matched [0: Transcode to ".to_string(),
+            format!("  {audio} (All): matched [1: Supported, 2: Transcode to "),
+            format!("  {subtitle} (WithOther): matched [3: no supported codec], "),
+            "Decision: transcode as required by requirement Video/All".to_string(),
+        ] {
+            assert!(
+                out.lines().any(|l| l.starts_with(&line)),
+                "{line:?} not in\n{out}"
+            );
+        }
+    }
+
+    #[test]
+    fn transcoded_streams_keep_indexes() {
+        let plan = plan(
+            CONFIG,
+            &vec![
+                video(0, AVCodecID::AV_CODEC_ID_HEVC),
+                audio(1, AVCodecID::AV_CODEC_ID_AAC, None),
+            ],
+        );
+        assert_eq!(
+            plan.reason.as_deref(),
+            Some("required by requirement Video/All")
+        );
+        assert_eq!(
+            args(&plan),
+            ["-map", "0:0", "-c:0", "h264", "-map", "0:1", "-c:1", "copy"]
+        );
+    }
 }
@@ -1,25 +1,52 @@
-use async_inotify::{WatchMask, Watcher as IWatcher};
-use inotify::{EventMask, WatchDescriptor};
-use log::{debug, trace, warn};
+use futures_util::StreamExt;
+use inotify::{EventMask, EventStream, Inotify, WatchDescriptor, WatchMask};
+use log::{debug, info, trace, warn};
 use std::{
     collections::HashMap,
     io,
     path::{self, Path, PathBuf},
     str::FromStr,
+    sync::{Arc, RwLock},
+    time::Instant,
 };
-use tokio::fs::{metadata, read_dir, remove_dir_all, remove_file, symlink_metadata};
+use tokio::fs::{metadata, read_dir, remove_dir, remove_dir_all, remove_file, symlink_metadata};
 
-use crate::transcoder::Transcoder;
+use crate::config::{ConfigLayer, WatchConfig};
+use crate::control;
+use crate::events::{self, Event};
+#[cfg(feature = "metrics")]
+use crate::metrics;
+use crate::plan::PlanEntry;
+use crate::probe::ProbeKey;
+use crate::queue::{Job, Queue};
+use crate::retry::FailureKind;
+use crate::state::{OutputRecord, State};
+use crate::transcoder::{Transcoder, TranscoderConfig, drylog, is_partial, remove_output};
 
 pub struct Watcher {
-    watcher: IWatcher,
-    descriptors: HashMap<WatchDescriptor, WatchPair>,
+    // Raw events are read, as async_inotify panics on events without watch, such as overflow
+    events: EventStream<[u8; 4096]>,
+    // Shared with worker, which looks up pairs of queued jobs, and with control API
+    descriptors: Pairs,
 }
 
+pub(crate) type Pairs = Arc<RwLock<HashMap<WatchDescriptor, WatchPair>>>;
+
+// Source directory with one or more destinations. Each destination gets own outputs planned with
+// its own configuration, while source files are probed once.
 #[derive(Clone, Debug)]
 pub struct WatchPair {
     pub src: PathBuf,
+    pub targets: Vec<WatchTarget>,
+    // Whether to check the whole source when watching starts
+    pub recheck: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct WatchTarget {
     pub dst: PathBuf,
+    // Overrides of the global configuration for this target only
+    pub overrides: ConfigLayer,
 }
 
 impl FromStr for WatchPair {
@@ -27,96 +54,373 @@ impl FromStr for WatchPair {
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let mut it = s.splitn(2, &[':', ',', '=', ';', ' '][..]);
-        let src = it.next().ok_or("Invalid format of watch pair")?.into();
-        let dst = it.next().ok_or("Invalid format of watch pair")?.into();
-        Ok(Self { src, dst })
+        let src = it.next().ok_or("Invalid format of watch pair")?;
+        let dst = it.next().ok_or("Invalid format of watch pair")?;
+        Ok(Self::new(src.into(), dst.into()))
+    }
+}
+
+impl From<WatchConfig> for WatchPair {
+    fn from(wc: WatchConfig) -> Self {
+        let targets = wc
+            .dst
+            .into_iter()
+            .map(|dst| WatchTarget {
+                dst,
+                overrides: wc.overrides.clone(),
+            })
+            .collect();
+        Self {
+            src: wc.src,
+            targets,
+            recheck: wc.recheck,
+        }
     }
 }
 
 impl WatchPair {
+    pub fn new(src: PathBuf, dst: PathBuf) -> Self {
+        Self {
+            src,
+            targets: vec![WatchTarget {
+                dst,
+                overrides: Default::default(),
+            }],
+            recheck: true,
+        }
+    }
+
     pub fn absolute(self) -> io::Result<Self> {
         Ok(Self {
+            recheck: self.recheck,
             src: path::absolute(self.src)?,
-            dst: path::absolute(self.dst)?,
+            targets: self
+                .targets
+                .into_iter()
+                .map(|target| {
+                    Ok(WatchTarget {
+                        dst: path::absolute(target.dst)?,
+                        ..target
+                    })
+                })
+                .collect::<io::Result<_>>()?,
         })
     }
+
+    // Joins pairs with the same source into one with several destinations. The first met target
+    // wins if several pairs have the same destination.
+    pub fn merge(pairs: impl IntoIterator<Item = WatchPair>) -> Vec<WatchPair> {
+        let mut merged = Vec::<WatchPair>::new();
+        for pair in pairs {
+            if let Some(same) = merged.iter_mut().find(|p| p.src == pair.src) {
+                same.recheck |= pair.recheck;
+                for target in pair.targets {
+                    if !same.targets.iter().any(|t| t.dst == target.dst) {
+                        same.targets.push(target);
+                    }
+                }
+            } else {
+                merged.push(pair);
+            }
+        }
+        merged
+    }
+}
+
+impl WatchTarget {
+    // Effective configuration of target: the global one with overrides for destination from
+    // `outputs` and then with overrides of target itself
+    pub fn config(&self) -> Result<TranscoderConfig, String> {
+        self.config_with(&TranscoderConfig::get())
+    }
+
+    pub fn config_with(&self, global: &TranscoderConfig) -> Result<TranscoderConfig, String> {
+        let global = global.for_output(&self.dst)?;
+        self.overrides.clone().apply(&global)
+    }
 }
 
 impl Watcher {
     pub fn new() -> Self {
+        let inotify = Inotify::init().expect("Failed to initialize inotify");
         Self {
-            watcher: IWatcher::init(),
+            events: inotify
+                .into_event_stream([0; 4096])
+                .expect("Failed to read inotify events"),
             descriptors: Default::default(),
         }
     }
 
     pub fn add(&mut self, wp: WatchPair) -> async_inotify::Result<()> {
         let wp = wp.absolute()?;
-        let wd = self.watcher.add(
+        let wd = self.events.watches().add(
             &wp.src,
-            &WatchMask::CREATE
+            WatchMask::CREATE
                 .union(WatchMask::DELETE)
                 .union(WatchMask::MOVED_TO)
                 .union(WatchMask::MOVED_FROM)
                 .union(WatchMask::CLOSE_WRITE),
         )?;
-        Self::recheck_fork(&wp.src, &wp.dst);
-        self.descriptors.insert(wd, wp);
+        if wp.recheck {
+            Self::recheck_fork(&wp);
+        }
+        self.descriptors.write().unwrap().insert(wd, wp);
         Ok(())
     }
 
+    // Starts processing of queued jobs, including the ones left from previous run. Should be
+    // called after pairs are added, as jobs of unknown pairs are dropped.
+    pub fn start_worker(&self) {
+        Queue::resume();
+        let descriptors = self.descriptors.clone();
+        tokio::spawn(async move {
+            loop {
+                let (id, job) = Queue::next().await;
+                let wp = descriptors
+                    .read()
+                    .unwrap()
+                    .values()
+                    .find(|wp| wp.src == job.root)
+                    .cloned();
+                let started = Instant::now();
+                let identity = ProbeKey::of(&job.src);
+                events::emit(Event::JobStarted { id, src: &job.src });
+                let results = match wp {
+                    Some(wp) => Self::process(&job.src, &wp, job.check_exists).await,
+                    None => {
+                        debug!("Dropping job for {:?} of detached {:?}", job.src, job.root);
+                        vec![]
+                    }
+                };
+                #[cfg(feature = "metrics")]
+                metrics::job_finished(&results);
+                let failure = results
+                    .iter()
+                    .filter_map(|(_, res)| res.as_ref().err())
+                    // Cancelled jobs are neither retried nor quarantined
+                    .filter(|err| !Transcoder::is_cancelled(err))
+                    .map(FailureKind::of)
+                    .max();
+                let failure = match failure {
+                    Some(FailureKind::CorruptInput) => {
+                        let src = job.src.clone();
+                        let kind = tokio::task::spawn_blocking(move || {
+                            FailureKind::CorruptInput.confirm(&src, identity)
+                        })
+                        .await;
+                        Some(kind.unwrap_or(FailureKind::Transient))
+                    }
+                    failure => failure,
+                };
+                match failure {
+                    Some(kind) => {
+                        events::emit(Event::JobFailed {
+                            id,
+                            src: &job.src,
+                            elapsed: started.elapsed(),
+                            kind,
+                            errors: results
+                                .iter()
+                                .filter_map(|(dst, res)| {
+                                    res.as_ref().err().map(|err| format!("{dst:?}: {err}"))
+                                })
+                                .collect(),
+                        });
+                        Queue::failed(id, job, kind)
+                    }
+                    None => {
+                        events::emit(Event::JobFinished {
+                            id,
+                            src: &job.src,
+                            elapsed: started.elapsed(),
+                        });
+                        Queue::done(id)
+                    }
+                }
+            }
+        });
+    }
+
+    // Starts serving control requests on socket
+    pub fn start_control(&self, socket: &Path) -> io::Result<()> {
+        control::serve(socket, self.descriptors.clone())
+    }
+
+    // Checks the whole source processing its files right away, without worker
     pub async fn recheck(wp: WatchPair) -> async_inotify::Result<()> {
         let wp = wp.absolute()?;
-        Self::check_f(&wp.src, &wp.src, &wp.dst).await;
+        Self::check_f(&wp.src, &wp, false).await;
+        Self::reconcile(&wp).await;
         Ok(())
     }
 
-    pub async fn watch(&mut self) {
-        loop {
-            if let Some(event) = self.watcher.next().await {
-                let wp = &self.descriptors[event.wd()];
-                let src = event.path().to_owned();
-                let mask = event.mask().clone();
-                let wp_src = wp.src.clone();
-                let wp_dst = wp.dst.clone();
-                tokio::spawn(async move {
-                    Self::do_action(&mask, &src, &wp_src, &wp_dst, false).await;
-                });
-            } else {
-                break;
+    // Plans outputs of all sources of pair without placing them
+    pub async fn plan(wp: WatchPair) -> io::Result<Vec<PlanEntry>> {
+        let wp = wp.absolute()?;
+        let mut entries = vec![];
+        Self::plan_f(&wp.src, &wp, &mut entries).await;
+        Ok(entries)
+    }
+
+    // Replaces watched pairs with new ones. Pairs with unchanged source keep their watch, but
+    // get rechecked when destinations changed.
+    pub fn update(&mut self, pairs: Vec<WatchPair>) -> async_inotify::Result<()> {
+        let pairs = pairs
+            .into_iter()
+            .map(WatchPair::absolute)
+            .collect::<io::Result<Vec<_>>>()?;
+        // Events already queued for detached pairs are ignored
+        self.descriptors.write().unwrap().retain(|wd, wp| {
+            let keep = pairs.iter().any(|pair| pair.src == wp.src);
+            if !keep {
+                info!("Detaching {:?}", wp.src);
+                if let Err(err) = self.events.watches().remove(wd.clone()) {
+                    debug!("Failed to remove watch of {:?}: {err}", wp.src);
+                }
+            }
+            keep
+        });
+        for pair in pairs {
+            let new = {
+                let mut descriptors = self.descriptors.write().unwrap();
+                if let Some(wp) = descriptors.values_mut().find(|wp| wp.src == pair.src) {
+                    let dsts = |wp: &WatchPair| {
+                        wp.targets.iter().map(|t| t.dst.clone()).collect::<Vec<_>>()
+                    };
+                    let recheck = pair.recheck && dsts(wp) != dsts(&pair);
+                    *wp = pair;
+                    if recheck {
+                        Self::recheck_fork(wp);
+                    }
+                    None
+                } else {
+                    Some(pair)
+                }
+            };
+            if let Some(pair) = new {
+                info!("Watching {:?} -> {:?}", pair.src, pair.targets);
+                self.add(pair)?;
             }
         }
+        Ok(())
     }
 
-    async fn do_action(event: &EventMask, f: &Path, src: &Path, dst: &Path, check_exists: bool) {
-        if let Ok(suffix) = f.strip_prefix(src) {
-            let dst = dst.join(suffix);
-            if dst == f {
-                warn!("Source and destination are same: {f:?}");
-                return;
+    pub async fn watch(&mut self) {
+        while self.next().await {}
+    }
+
+    // Waits for the next event and spawns its processing. Returns false when no more events will
+    // come.
+    pub async fn next(&mut self) -> bool {
+        let event = match self.events.next().await {
+            Some(Ok(event)) => event,
+            Some(Err(err)) => {
+                warn!("Failed to read inotify events: {err}");
+                return false;
             }
+            None => return false,
+        };
+        // Overflow refers no watch, any source may have missed changes
+        if event.mask.contains(EventMask::Q_OVERFLOW) {
+            warn!("Queue of inotify events overflowed, rechecking all sources");
+            #[cfg(feature = "metrics")]
+            metrics::overflow();
+            for wp in self.descriptors.read().unwrap().values() {
+                Self::recheck_fork(wp);
+            }
+            return true;
+        }
+        let wp = self.descriptors.read().unwrap().get(&event.wd).cloned();
+        if let Some(wp) = wp {
+            #[cfg(feature = "metrics")]
+            metrics::event(&wp.src);
+            let src = match &event.name {
+                Some(name) => wp.src.join(name),
+                None => wp.src.clone(),
+            };
+            let mask = event.mask;
+            tokio::spawn(async move {
+                Self::do_action(&mask, &src, &wp, false, true).await;
+            });
+        } else {
+            trace!(
+                "Ignoring event {:?} of detached {:?}",
+                event.mask, event.name
+            );
+        }
+        true
+    }
+
+    // Sources are either queued for worker of watcher or, as on one-shot scan, processed right
+    // away
+    async fn do_action(
+        event: &EventMask,
+        f: &Path,
+        wp: &WatchPair,
+        check_exists: bool,
+        queued: bool,
+    ) {
+        if f.starts_with(&wp.src) {
             trace!("Processing {event:?} on {f:?}");
             if event.intersects(EventMask::DELETE.union(EventMask::MOVED_FROM)) {
-                debug!("Removing {dst:?}");
-                if let Err(err) = Self::delete(&dst).await {
-                    warn!("Failed to delete {dst:?}: {err:?}");
+                if Transcoder::cancel(f) {
+                    info!("Cancelled processing of removed {f:?}");
+                }
+                State::forget_source(f);
+                for (dst, _) in Self::destinations(f, wp).iter() {
+                    // Transcoded output may have another extension than the source
+                    if let Some(record) = State::forget(dst)
+                        && record.output != *dst
+                    {
+                        debug!("Removing {:?}", record.output);
+                        match remove_output(&record.output) {
+                            Ok(()) => events::emit(Event::Removed {
+                                output: &record.output,
+                                reason: "source removed",
+                            }),
+                            Err(err) => warn!("Failed to delete {:?}: {err:?}", record.output),
+                        }
+                    }
+                    if symlink_metadata(dst).await.is_ok() {
+                        debug!("Removing {dst:?}");
+                        match Self::delete(dst).await {
+                            Ok(()) => events::emit(Event::Removed {
+                                output: dst,
+                                reason: "source removed",
+                            }),
+                            Err(err) => warn!("Failed to delete {dst:?}: {err:?}"),
+                        }
+                    }
                 }
             } else if event.intersects(
                 EventMask::CREATE
                     .union(EventMask::MOVED_TO)
                     .union(EventMask::CLOSE_WRITE),
             ) {
+                // Changed source is processed anew by fresh job
+                if event.intersects(EventMask::MOVED_TO.union(EventMask::CLOSE_WRITE))
+                    && Transcoder::cancel(f)
+                {
+                    info!("Cancelled processing of changed {f:?}");
+                }
                 if Self::is_dir(f).await {
-                    trace!("Ignoring directory {f:?}")
-                } else {
-                    if dst.exists() && check_exists {
-                        trace!("Ignoring existed {f:?}")
-                    } else {
-                        debug!("Performing emplacing {f:?} to {dst:?}");
-                        if let Err(err) = Transcoder::get().transcode(f, &dst) {
-                            warn!("Failed to transcode {src:?} into {dst:?}: {err}");
-                        }
-                    }
+                    trace!("Ignoring directory {f:?}");
+                    return;
+                }
+                events::emit(Event::Discovered {
+                    src: f,
+                    pair: &wp.src,
+                });
+                if TranscoderConfig::get().dryrun || !queued {
+                    Self::process(f, wp, check_exists).await;
+                } else if !Self::pending(f, wp, check_exists).is_empty() {
+                    Queue::push(Job {
+                        src: f.to_owned(),
+                        root: wp.src.clone(),
+                        check_exists,
+                        attempt: 0,
+                        not_before: None,
+                    });
                 }
             } else {
                 warn!("{:?}: {:?} -> unexpected event", event, f);
@@ -126,26 +430,328 @@ impl Watcher {
         }
     }
 
-    fn recheck_fork(src: &Path, dst: &Path) {
-        let src = src.to_owned();
-        let dst = dst.to_owned();
-        tokio::spawn(async move { Self::check_f(&src, &src, &dst).await });
+    // Places outputs of source into destinations which need it. Returns results of placed ones
+    async fn process(
+        f: &Path,
+        wp: &WatchPair,
+        check_exists: bool,
+    ) -> Vec<(PathBuf, io::Result<()>)> {
+        if metadata(f).await.is_err() {
+            debug!("Source {f:?} is gone");
+            return vec![];
+        }
+        let dst = Self::pending(f, wp, check_exists);
+        if dst.is_empty() {
+            return vec![];
+        }
+        debug!(
+            "Performing emplacing {f:?} to {:?}",
+            dst.iter().map(|(dst, _)| dst).collect::<Vec<_>>()
+        );
+        let src = f.to_owned();
+        let results = tokio::task::spawn_blocking(move || {
+            let results = Transcoder::get().transcode(&src, &dst, check_exists);
+            dst.into_iter()
+                .map(|(dst, _)| dst)
+                .zip(results)
+                .collect::<Vec<_>>()
+        })
+        .await;
+        let results = match results {
+            Ok(results) => results,
+            Err(err) => {
+                warn!("Transcoding of {f:?} failed: {err}");
+                return vec![];
+            }
+        };
+        for (dst, res) in results.iter() {
+            match res {
+                Err(err) if Transcoder::is_cancelled(err) => debug!("Not placed {dst:?}: {err}"),
+                Err(err) => warn!("Failed to transcode {f:?} into {dst:?}: {err}"),
+                Ok(()) => (),
+            }
+        }
+        results
+    }
+
+    fn destinations<'a>(f: &Path, wp: &'a WatchPair) -> Vec<(PathBuf, &'a WatchTarget)> {
+        let Ok(suffix) = f.strip_prefix(&wp.src) else {
+            return vec![];
+        };
+        let mut dst = vec![];
+        for target in wp.targets.iter() {
+            let dst_f = target.dst.join(suffix);
+            if dst_f == f {
+                warn!("Source and destination are same: {f:?}");
+            } else {
+                dst.push((dst_f, target));
+            }
+        }
+        dst
+    }
+
+    // Destinations with their configurations, which outputs should be placed
+    fn pending(f: &Path, wp: &WatchPair, check_exists: bool) -> Vec<(PathBuf, TranscoderConfig)> {
+        if State::is_quarantined(f) {
+            drylog!(TranscoderConfig::get(), "Ignoring quarantined {f:?}");
+            return vec![];
+        }
+        let mut dst: Vec<_> = Self::destinations(f, wp)
+            .into_iter()
+            .filter_map(|(dst, target)| match target.config() {
+                Ok(config) => Some((dst, config)),
+                Err(err) => {
+                    warn!("Invalid configuration for {dst:?}: {err}");
+                    None
+                }
+            })
+            .collect();
+        if check_exists {
+            dst.retain(|(dst, config)| {
+                let actual = Self::is_actual(dst, config);
+                if actual {
+                    trace!("Ignoring actual {dst:?}")
+                }
+                !actual
+            });
+        }
+        dst
+    }
+
+    // Rechecks the part of source. Destinations are reconciled only when the whole source is
+    // rechecked
+    pub(crate) fn recheck_path(path: PathBuf, wp: WatchPair) {
+        if path == wp.src {
+            return Self::recheck_fork(&wp);
+        }
+        tokio::spawn(async move {
+            Self::check_f(&path, &wp, true).await;
+        });
+    }
+
+    // Rechecks the whole source of watched pair queueing its files
+    fn recheck_fork(wp: &WatchPair) {
+        let wp = wp.clone();
+        tokio::spawn(async move {
+            Self::check_f(&wp.src, &wp, true).await;
+            Self::reconcile(&wp).await;
+        });
+    }
+
+    // Removes outputs of sources deleted while not watched, dangling symlinks and empty
+    // directories from destinations
+    async fn reconcile(wp: &WatchPair) {
+        // Empty source is likely an unmounted storage, do not wipe the destinations because of it
+        let has_entries = match read_dir(&wp.src).await {
+            Ok(mut dir) => matches!(dir.next_entry().await, Ok(Some(_))),
+            Err(_) => false,
+        };
+        if !has_entries {
+            warn!("Source {:?} is empty, skipping reconciliation", wp.src);
+            return;
+        }
+        let config = TranscoderConfig::get();
+        for target in wp.targets.iter() {
+            trace!("Reconciling {:?}", target.dst);
+            // State maps outputs to their sources, as transcoded ones have another extension
+            let recorded: HashMap<_, _> = State::outputs_under(&target.dst)
+                .into_iter()
+                .map(|(dst, record)| (record.output.clone(), (dst, record)))
+                .collect();
+            Self::remove_orphans(&target.dst, target, wp, &recorded, &config).await;
+            Self::clean_dir(&target.dst, &target.dst, &config).await;
+        }
+    }
+
+    async fn remove_orphans(
+        dir: &Path,
+        target: &WatchTarget,
+        wp: &WatchPair,
+        recorded: &HashMap<PathBuf, (PathBuf, OutputRecord)>,
+        config: &TranscoderConfig,
+    ) {
+        let Ok(mut entries) = read_dir(dir).await else {
+            return;
+        };
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let path = entry.path();
+            let Ok(file_type) = entry.file_type().await else {
+                continue;
+            };
+            if file_type.is_dir() {
+                if path != wp.src {
+                    Box::pin(Self::remove_orphans(&path, target, wp, recorded, config)).await;
+                }
+                continue;
+            }
+            // Outputs being written have neither record nor source of the same name yet
+            if is_partial(&path) || State::is_partial(&path) {
+                trace!("Keeping {path:?}, as it is being written");
+                continue;
+            }
+            let record = recorded.get(&path);
+            let exists = match record {
+                Some((_, record)) if !record.src.starts_with(&wp.src) => continue,
+                Some((_, record)) => Self::exists(&record.src).await,
+                // Outputs placed before the state was recorded have the name of source, but
+                // maybe another extension
+                None => match path.strip_prefix(&target.dst) {
+                    Ok(suffix) => Self::exists_with_stem(&wp.src.join(suffix)).await,
+                    Err(_) => continue,
+                },
+            };
+            match exists {
+                Ok(true) => continue,
+                Ok(false) => (),
+                Err(err) => {
+                    debug!("Keeping {path:?}, as its source is not accessible: {err}");
+                    continue;
+                }
+            }
+            drylog!(config, "Removing orphaned {path:?}");
+            if config.dryrun {
+                continue;
+            }
+            match remove_output(&path) {
+                Ok(()) => events::emit(Event::Removed {
+                    output: &path,
+                    reason: "orphan",
+                }),
+                Err(err) => warn!("Failed to delete {path:?}: {err:?}"),
+            }
+            if let Some((dst, record)) = record {
+                State::forget(dst);
+                State::forget_source(&record.src);
+            }
+        }
     }
 
-    async fn check_f(f: &Path, src: &Path, dst: &Path) {
-        trace!("Rechecking {f:?} ({src:?} -> {dst:?})");
+    // Only missing source is considered deleted, not the one failed to be read
+    async fn exists(src: &Path) -> io::Result<bool> {
+        match symlink_metadata(src).await {
+            Ok(_) => Ok(true),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    async fn exists_with_stem(src: &Path) -> io::Result<bool> {
+        if Self::exists(src).await? {
+            return Ok(true);
+        }
+        let (Some(dir), Some(stem)) = (src.parent(), src.file_stem()) else {
+            return Ok(false);
+        };
+        let mut entries = match read_dir(dir).await {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(err) => return Err(err),
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            if Path::new(&entry.file_name()).file_stem() == Some(stem) {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    // Returns whether directory became empty
+    async fn clean_dir(dir: &Path, root: &Path, config: &TranscoderConfig) -> bool {
+        let dryrun = config.dryrun;
+        let Ok(mut entries) = read_dir(dir).await else {
+            return false;
+        };
+        let mut empty = true;
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let path = entry.path();
+            let Ok(file_type) = entry.file_type().await else {
+                empty = false;
+                continue;
+            };
+            let removed = if file_type.is_dir() {
+                Box::pin(Self::clean_dir(&path, root, config)).await
+            } else if file_type.is_symlink() && metadata(&path).await.is_err() {
+                drylog!(config, "Removing dangling symlink {path:?}");
+                let removed = dryrun || remove_file(&path).await.is_ok();
+                if removed && !dryrun {
+                    events::emit(Event::Removed {
+                        output: &path,
+                        reason: "dangling symlink",
+                    });
+                }
+                removed
+            } else {
+                false
+            };
+            empty &= removed;
+        }
+        if empty && dir != root {
+            drylog!(config, "Removing empty directory {dir:?}");
+            return dryrun || remove_dir(dir).await.is_ok();
+        }
+        false
+    }
+
+    async fn plan_f(f: &Path, wp: &WatchPair, entries: &mut Vec<PlanEntry>) {
+        if Self::is_dir(f).await {
+            if let Ok(mut dir) = read_dir(f).await {
+                while let Ok(Some(entry)) = dir.next_entry().await {
+                    Box::pin(Self::plan_f(&entry.path(), wp, entries)).await
+                }
+            }
+            return;
+        }
+        let quarantined = State::is_quarantined(f);
+        let mut planned = vec![];
+        for (dst, target) in Self::destinations(f, wp) {
+            match target.config() {
+                Ok(_) if quarantined => entries.push(PlanEntry::skipped(f, &dst, "quarantined")),
+                Ok(config) if Self::is_actual(&dst, &config) => {
+                    entries.push(PlanEntry::skipped(f, &dst, "output is actual"))
+                }
+                Ok(config) => planned.push((dst, config)),
+                Err(err) => warn!("Invalid configuration for {dst:?}: {err}"),
+            }
+        }
+        if planned.is_empty() {
+            return;
+        }
+        let plans = Transcoder::plan(f, &planned);
+        entries.extend(
+            planned
+                .iter()
+                .zip(plans)
+                .map(|((dst, _), plan)| PlanEntry::planned(dst, plan)),
+        );
+    }
+
+    async fn check_f(f: &Path, wp: &WatchPair, queued: bool) {
+        trace!("Rechecking {f:?} ({:?})", wp.src);
         if Self::is_dir(f).await {
             if let Ok(mut dir) = read_dir(f).await {
                 while let Ok(f) = dir.next_entry().await {
                     if let Some(f) = f {
-                        Box::pin(Self::check_f(&f.path(), src, dst)).await
+                        Box::pin(Self::check_f(&f.path(), wp, queued)).await
                     } else {
                         break;
                     }
                 }
             }
         } else {
-            Self::do_action(&EventMask::CREATE, f, src, dst, true).await;
+            Self::do_action(&EventMask::CREATE, f, wp, true, queued).await;
+        }
+    }
+
+    // Output is actual when it was built from the same source with the same configuration.
+    // Outputs placed before the state was recorded are considered actual while exist.
+    fn is_actual(dst: &Path, config: &TranscoderConfig) -> bool {
+        match State::output(dst) {
+            Some(record) => {
+                record.config == config.fingerprint()
+                    && record.output.symlink_metadata().is_ok()
+                    && !record.is_source_changed()
+            }
+            None => dst.exists(),
         }
     }
 
@@ -167,3 +773,32 @@ impl Watcher {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[tokio::test]
+    async fn reconcile_keeps_partial_outputs() {
+        let root =
+            std::env::temp_dir().join(format!("transcoder-reconcile-{}", std::process::id()));
+        let (src, dst) = (root.join("src"), root.join("dst"));
+        fs::create_dir_all(&src).unwrap();
+        fs::create_dir_all(&dst).unwrap();
+        fs::write(src.join("a.flac"), "").unwrap();
+        for name in ["a.opus", ".b.partial.opus", "c.opus", "d.tmp"] {
+            fs::write(dst.join(name), "").unwrap();
+        }
+        // Output being written under a name other than the usual one
+        State::record_partial(&dst.join("d.tmp"));
+        Watcher::reconcile(&WatchPair::new(src, dst.clone())).await;
+        let exists = |name| dst.join(name).exists();
+        assert!(exists("a.opus"));
+        assert!(exists(".b.partial.opus"));
+        assert!(exists("d.tmp"));
+        assert!(!exists("c.opus"));
+        State::forget_partial(&dst.join("d.tmp"));
+        fs::remove_dir_all(&root).unwrap();
+    }
+}